@@ -0,0 +1,74 @@
+//! Compares the construction and lookup of the default suffix array (suffix crate) with the parallel one (--fast-index)
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use suffix::SuffixTable;
+
+//the unit tests of the module are compiled along but not run from here
+#[path = "../src/suffixarray.rs"]
+#[allow(dead_code)]
+mod suffixarray;
+
+///Generate a deterministic pseudo-random text of roughly the given size in bytes
+fn sample_text(size: usize) -> String {
+    let words = [
+        "the",
+        "good",
+        "bad",
+        "republic",
+        "justice",
+        "is",
+        "of",
+        "and",
+        "city",
+        "soul",
+        "we",
+        "say",
+        "Socrates",
+        "a",
+        "to",
+        "in",
+        "that",
+        "knowledge",
+        "über",
+        "état",
+    ];
+    let mut text = String::with_capacity(size + 16);
+    let mut state: u64 = 42;
+    while text.len() < size {
+        //linear congruential generator
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        text.push_str(words[(state >> 33) as usize % words.len()]);
+        text.push(if (state >> 20) & 0xf == 0 { '\n' } else { ' ' });
+    }
+    text
+}
+
+fn bench_build(c: &mut Criterion) {
+    let text = sample_text(1_000_000);
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    group.bench_function("suffix", |b| b.iter(|| SuffixTable::new(text.as_str())));
+    group.bench_function("fast-index", |b| {
+        b.iter(|| suffixarray::SuffixArray::new(text.as_str()).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_positions(c: &mut Criterion) {
+    let text = sample_text(1_000_000);
+    let suffixtable = SuffixTable::new(text.as_str());
+    let suffixarray = suffixarray::SuffixArray::new(text.as_str()).unwrap();
+    let mut group = c.benchmark_group("positions");
+    group.bench_function("suffix", |b| {
+        b.iter(|| suffixtable.positions("justice").len())
+    });
+    group.bench_function("fast-index", |b| {
+        b.iter(|| suffixarray.positions("justice").len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_build, bench_positions);
+criterion_main!(benches);
@@ -1,116 +1,4351 @@
+extern crate aho_corasick;
+extern crate caseless;
 extern crate clap;
+extern crate csv;
+extern crate encoding_rs;
+extern crate flate2;
+extern crate fst;
+extern crate rayon;
+extern crate regex;
 extern crate suffix;
+extern crate toml;
+extern crate unicode_normalization;
+extern crate xxhash_rust;
 
-use clap::{App, Arg};
-use std::collections::HashSet;
+use aho_corasick::AhoCorasick;
+use clap::{App, Arg, ArgMatches, ValueSource};
+use encoding_rs::Encoding;
+use flate2::read::GzDecoder;
+use rayon::prelude::*;
+use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::io::{stdin, BufRead, BufReader, Read};
+use std::io::{stderr, stdin, stdout, BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
 use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use suffix::SuffixTable;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use xxhash_rust::xxh3::Xxh3;
+
+#[cfg(feature = "fast-index")]
+mod suffixarray;
 
 type Lexicon = HashSet<String>;
+///A lexicon compiled into a finite state transducer (see --fst)
+type FstLexicon = fst::Set<Vec<u8>>;
+///Weight of each entry of a lexicon (see --weighted-coverage)
+type Weights = HashMap<String, f64>;
+///An entry of a lexicon file and its value (the second column), if any
+type LexiconRecord = (String, Option<String>);
+
+///Errors that cause lexmatch to stop, each results in a distinct exit code
+#[derive(Debug)]
+enum LexmatchError {
+    ///Invalid arguments or an invalid combination of arguments
+    Arguments(String),
+    ///An input or output file could not be read/written
+    File(String, std::io::Error),
+    ///Any other I/O error (e.g. writing the output)
+    Io(std::io::Error),
+    ///The run completed but nothing matched
+    NoMatches,
+    ///None of the lexicons could be read (with --skip-bad-lexicons)
+    NoLexicons,
+    ///The maximum number of matches has been output (--limit), this stops the search but is not an error
+    LimitReached,
+    ///The coverage (over all lexicons) is below the minimum (--min-coverage)
+    CoverageBelow(f64, f64),
+}
+
+impl LexmatchError {
+    fn exitcode(&self) -> i32 {
+        match self {
+            Self::LimitReached => 0,
+            Self::NoMatches => 1,
+            Self::Arguments(_) => 2,
+            Self::File(..) | Self::Io(_) | Self::NoLexicons => 3,
+            Self::CoverageBelow(..) => 4,
+        }
+    }
+}
+
+impl fmt::Display for LexmatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Arguments(msg) => write!(f, "{}", msg),
+            Self::File(filename, err) => write!(f, "{}: {}", filename, err),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::NoMatches => write!(f, "no matches found"),
+            Self::NoLexicons => write!(f, "none of the lexicons could be read"),
+            Self::LimitReached => write!(f, "match limit reached"),
+            Self::CoverageBelow(coverage, min_coverage) => write!(
+                f,
+                "coverage {} is below the minimum of {}",
+                coverage, min_coverage
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LexmatchError {}
+
+impl From<std::io::Error> for LexmatchError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+///Determines which characters delimit an exact match (see --boundary)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Boundary {
+    ///Anything that is not alphanumeric is a boundary
+    Alnum,
+    ///Only whitespace is a boundary
+    Whitespace,
+    ///Anything that is not alphabetic is a boundary (so also digits)
+    Word,
+}
+
+impl FromStr for Boundary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alnum" => Ok(Self::Alnum),
+            "whitespace" => Ok(Self::Whitespace),
+            "word" => Ok(Self::Word),
+            _ => Err(format!("unknown boundary mode: {}", s)),
+        }
+    }
+}
+
+impl Boundary {
+    fn is_boundary(&self, c: char) -> bool {
+        match self {
+            Self::Alnum => !c.is_alphanumeric(),
+            Self::Whitespace => c.is_whitespace(),
+            Self::Word => !c.is_alphabetic(),
+        }
+    }
+}
+
+///Ranges of characters that are considered alphanumeric (see --alnum-chars)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CharRanges(Vec<(char, char)>);
+
+impl FromStr for CharRanges {
+    type Err = String;
+
+    ///Parses ranges like a character class in a regular expression (e.g. a-zA-Z0-9), a hyphen at the start or end is taken literally
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut ranges = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            if i + 2 < chars.len() && chars[i + 1] == '-' {
+                if chars[i] > chars[i + 2] {
+                    return Err(format!("invalid range: {}-{}", chars[i], chars[i + 2]));
+                }
+                ranges.push((chars[i], chars[i + 2]));
+                i += 3;
+            } else {
+                ranges.push((chars[i], chars[i]));
+                i += 1;
+            }
+        }
+        if ranges.is_empty() {
+            return Err("no characters given".to_string());
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl CharRanges {
+    fn contains(&self, c: char) -> bool {
+        self.0.iter().any(|(first, last)| c >= *first && c <= *last)
+    }
+}
+
+///Pairs of opening and closing delimiters of regions in which matches are skipped (see --skip-inside)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DelimiterPairs(Vec<(char, char)>);
+
+impl FromStr for DelimiterPairs {
+    type Err = String;
+
+    ///Parses a string of consecutive pairs of an opening and closing character, e.g. ()[]"" (the opening and closing character may be the same)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        let pairs = chars.chunks_exact(2);
+        if chars.is_empty() || !pairs.remainder().is_empty() {
+            return Err("expected pairs of opening and closing characters".to_string());
+        }
+        Ok(Self(pairs.map(|pair| (pair[0], pair[1])).collect()))
+    }
+}
+
+impl DelimiterPairs {
+    ///Find the regions of the text that are enclosed by any of the delimiter pairs, including the delimiters themselves.
+    ///Nesting of the same pair is respected, a region that is not closed is ignored. The regions are sorted and disjoint.
+    fn regions(&self, text: &str) -> Vec<(usize, usize)> {
+        let mut regions: Vec<(usize, usize)> = Vec::new();
+        for (open, close) in self.0.iter() {
+            let mut depth = 0;
+            let mut begin = 0;
+            for (i, c) in text.char_indices() {
+                if depth > 0 && c == *close {
+                    depth -= 1;
+                    if depth == 0 {
+                        regions.push((begin, i + c.len_utf8()));
+                    }
+                } else if c == *open {
+                    if depth == 0 {
+                        begin = i;
+                    }
+                    depth += 1;
+                }
+            }
+        }
+        regions.sort_unstable();
+        let mut merged: Vec<(usize, usize)> = Vec::with_capacity(regions.len());
+        for (begin, end) in regions {
+            match merged.last_mut() {
+                Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((begin, end)),
+            }
+        }
+        merged
+    }
+}
+
+///A part of an output template (see --format)
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FormatPart {
+    Literal(String),
+    Text,
+    Begin,
+    End,
+    Lexicon,
+    File,
+    Count,
+}
+
+///A template for the verbose output, with placeholders for the columns (see --format)
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Format(Vec<FormatPart>);
+
+impl FromStr for Format {
+    type Err = String;
+
+    ///Parses a template like "{begin}\t{end}\t{text}", the escapes \t and \n and doubled braces are supported as well
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '\\' if chars.peek() == Some(&'t') => {
+                    chars.next();
+                    literal.push('\t');
+                }
+                '\\' if chars.peek() == Some(&'n') => {
+                    chars.next();
+                    literal.push('\n');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed placeholder: {{{}", name)),
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "text" => FormatPart::Text,
+                        "begin" => FormatPart::Begin,
+                        "end" => FormatPart::End,
+                        "lexicon" => FormatPart::Lexicon,
+                        "file" => FormatPart::File,
+                        "count" => FormatPart::Count,
+                        _ => return Err(format!("unknown placeholder: {{{}}}", name)),
+                    };
+                    if !literal.is_empty() {
+                        parts.push(FormatPart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                }
+                '}' => return Err("unmatched }, use }} for a literal brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(FormatPart::Literal(literal));
+        }
+        Ok(Self(parts))
+    }
+}
+
+impl Format {
+    ///Does the template output the number of occurrences
+    fn has_count(&self) -> bool {
+        self.0.contains(&FormatPart::Count)
+    }
+}
+
+///Determines how differences in case are handled (see --no-case, --ascii-no-case and --case-fold)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Case {
+    ///Case sensitive matching
+    Sensitive,
+    ///Lexicons and text are lowercased
+    Lower,
+    ///Only ASCII letters in lexicons and text are lowercased, this never changes the length of the text
+    Ascii,
+    ///Lexicons and text are case folded according to Unicode (e.g. ß becomes ss)
+    Fold,
+}
+
+impl Case {
+    fn convert(&self, text: String) -> String {
+        match self {
+            Self::Sensitive => text,
+            Self::Lower => text.to_lowercase(),
+            Self::Ascii => {
+                let mut text = text;
+                text.make_ascii_lowercase();
+                text
+            }
+            Self::Fold => caseless::default_case_fold_str(&text),
+        }
+    }
+}
+
+///Determines what the coverage is computed against (see --coverage-denominator)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CoverageDenominator {
+    ///Tokens with at least one alphabetic character (numbers are not counted)
+    AlphaTokens,
+    ///All tokens, including numbers
+    AllTokens,
+    ///All characters except whitespace
+    Chars,
+}
+
+impl FromStr for CoverageDenominator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "alpha-tokens" => Ok(Self::AlphaTokens),
+            "all-tokens" => Ok(Self::AllTokens),
+            "chars" => Ok(Self::Chars),
+            _ => Err(format!("unknown coverage denominator: {}", s)),
+        }
+    }
+}
+
+///Determines from which side of the comparison diacritics are removed (see --fold-diacritics)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FoldDiacritics {
+    ///Only from the lexicons, for accented lexicons and unaccented text
+    Lexicon,
+    ///Only from the text, for unaccented lexicons and accented text
+    Text,
+    ///From both the lexicons and the text
+    Both,
+}
+
+impl FoldDiacritics {
+    fn lexicon(&self) -> bool {
+        *self != Self::Text
+    }
+
+    fn text(&self) -> bool {
+        *self != Self::Lexicon
+    }
+}
+
+impl FromStr for FoldDiacritics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lexicon" => Ok(Self::Lexicon),
+            "text" => Ok(Self::Text),
+            "both" => Ok(Self::Both),
+            _ => Err(format!("unknown side to fold diacritics on: {}", s)),
+        }
+    }
+}
+
+///Read a TOML configuration file and convert it to command-line arguments, for all settings that were not already passed on the command line
+fn read_config(app: &App, args: &ArgMatches, filename: &str) -> Result<Vec<String>, LexmatchError> {
+    eprintln!("Reading configuration from {}...", filename);
+    let data = std::fs::read_to_string(filename)
+        .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+    let config: toml::value::Table = toml::from_str(&data).map_err(|err| {
+        LexmatchError::File(
+            filename.to_string(),
+            std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+        )
+    })?;
+    let mut extra = Vec::new();
+    for (key, value) in config.iter() {
+        let arg = app
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(key.as_str()) && key != "config")
+            .ok_or_else(|| {
+                LexmatchError::Arguments(format!("Unknown setting in {}: {}", filename, key))
+            })?;
+        if args.value_source(arg.get_id()) == Some(ValueSource::CommandLine) {
+            //the command line takes precedence
+            continue;
+        }
+        let values: Vec<&toml::Value> = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(set) if !arg.is_takes_value_set() => {
+                    if *set {
+                        extra.push(format!("--{}", key));
+                    }
+                }
+                toml::Value::String(value) if arg.is_takes_value_set() => {
+                    extra.push(format!("--{}={}", key, value));
+                }
+                toml::Value::Integer(_) | toml::Value::Float(_) if arg.is_takes_value_set() => {
+                    extra.push(format!("--{}={}", key, value));
+                }
+                _ => {
+                    return Err(LexmatchError::Arguments(format!(
+                        "Invalid value for {} in {}: {}",
+                        key, filename, value
+                    )))
+                }
+            }
+        }
+    }
+    Ok(extra)
+}
+
+///Parse the value of an argument, if it was provided
+fn parse_arg<T: FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, LexmatchError> {
+    args.value_of(name)
+        .map(|value| {
+            value.parse::<T>().map_err(|_| {
+                LexmatchError::Arguments(format!("Invalid value for --{}: {}", name, value))
+            })
+        })
+        .transpose()
+}
+
+///Options for a run, parsed and validated once from the command-line arguments
+struct Options {
+    lexicons: Vec<String>,
+    ///Names to use for the lexicons in the output, in the same order as the lexicons
+    lexicon_names: Vec<String>,
+    ///Names of the lexicons that have no name in lexicon_names: the filename as given, or only the file name for those from --lexicon-dir
+    lexicon_default_names: Vec<String>,
+    queries: Vec<String>,
+    query_file: Option<String>,
+    stoplist: Option<String>,
+    textfiles: Vec<String>,
+    output: Option<String>,
+    folia: Option<String>,
+    extract: Option<String>,
+    all: bool,
+    no_case: bool,
+    ///Only fold the case of ASCII letters
+    ascii_no_case: bool,
+    ///Unicode case folding rather than lowercasing
+    case_fold: bool,
+    verbose: bool,
+    count_only: bool,
+    tokens: bool,
+    ///Maximum pattern length in characters, if --cjk is set
+    cjk: Option<usize>,
+    ///Minimum pattern length in characters for --cjk
+    cjk_min: usize,
+    cjk_tile: bool,
+    ///Look up each line of the text as a whole
+    whole_line: bool,
+    coverage: bool,
+    coverage_matrix: bool,
+    ///The frequency threshold for lexicons without a threshold of their own (the last --freq)
+    freq: usize,
+    ///The frequency thresholds given for each lexicon, in the same order as the lexicons
+    lexicon_freqs: Vec<usize>,
+    max_freq: Option<usize>,
+    ///Minimum length of tokens (--tokens) or lexicon entries (suffix array) in characters
+    min_token_length: usize,
+    ///Output at most this many match offsets per entry (the earliest ones in the text)
+    max_matches: Option<usize>,
+    line_col: bool,
+    no_cross_newline: bool,
+    sort: bool,
+    oov: bool,
+    threads: usize,
+    aho_corasick: bool,
+    stats: bool,
+    report_misses: bool,
+    distinct_count: bool,
+    relative: bool,
+    normalize_newlines: bool,
+    stream: bool,
+    no_numbers: bool,
+    stable_columns: bool,
+    boundary: Boundary,
+    inclusive_end: bool,
+    ///Column to match against if the text is TSV (1-based)
+    text_column: Option<usize>,
+    fast_index: bool,
+    ///Output the length of each match in characters
+    with_length: bool,
+    ///Only report on the lexicons, don't process any text
+    validate_lexicons: bool,
+    ///Only print a hash of each loaded lexicon, don't process any text
+    lexicon_hash: bool,
+    ///Keep only the longest of the matches that start at the same position
+    longest_only: bool,
+    ///Also drop matches that are contained in a longer match (with --longest-only)
+    no_contained: bool,
+    ///Output the matches for each lexicon entry as a JSON object
+    json_grouped: bool,
+    ///Replace invalid UTF-8 in the text rather than failing
+    lossy: bool,
+    ///Output whether each match is at the start of a line
+    mark_line_start: bool,
+    ///Build a single index over all texts
+    concat: bool,
+    ///Report the time spent in each phase
+    benchmark: bool,
+    ///Output the number of matches for each text and lexicon at the end
+    summary_matrix: bool,
+    ///Output the number of matches for each lexicon entry and text at the end
+    entry_matrix: bool,
+    ///Collapse runs of whitespace in text and lexicons before matching
+    collapse_whitespace: bool,
+    ///What the coverage is computed against
+    coverage_denominator: CoverageDenominator,
+    ///Remove diacritics from the lexicons and/or the text before matching
+    fold_diacritics: Option<FoldDiacritics>,
+    ///Match raw bytes rather than UTF-8 text
+    bytes: bool,
+    ///Don't append a newline to the text after reading it
+    no_append_newline: bool,
+    ///Output all tokens in CoNLL-U format
+    conllu: bool,
+    ///Only report the first occurrence of each lexicon entry
+    first_match_only: bool,
+    ///Output the index of the lexicon rather than its name
+    lexicon_ids: bool,
+    ///Restricts which characters are alphanumeric (for tokenization and boundaries)
+    alnum_chars: Option<CharRanges>,
+    ///Matches must align with the tokens in the text rather than be delimited by boundaries
+    exact_phrase: bool,
+    ///Added to all offsets in the output
+    offset_base: u64,
+    ///Exact matches only need a boundary before them, so they match at the start of a word
+    prefix: bool,
+    ///Exact matches only need a boundary after them, so they match at the end of a word
+    suffix: bool,
+    ///Don't report progress
+    quiet: bool,
+    ///Template for the verbose output
+    format: Option<Format>,
+    ///Lexicon with entries that veto the matches they overlap with
+    exclude_lexicon: Option<String>,
+    ///Read lexicons as tab separated values with CSV-style double-quote quoting
+    csv_lexicon: bool,
+    ///Output the matches of each lexicon together, preceded by a line with the name of the lexicon
+    group_by_lexicon: bool,
+    ///Stop after this many matches have been output
+    limit: Option<usize>,
+    ///Output the header as a JSON array of column names
+    header_json: bool,
+    ///Leave out the text of each match in the verbose output, only output the offsets
+    positions_only: bool,
+    ///Output whether each match is contained in a longer match
+    mark_contained: bool,
+    ///Continue with the other lexicons if a lexicon can not be read
+    skip_bad_lexicons: bool,
+    ///Weigh each match by the weight of the entry in the second column of the lexicon for the coverage
+    weighted_coverage: bool,
+    ///Output the fraction of characters of each line that is covered by a match, rather than the matches
+    density: bool,
+    ///Output the stretches of the text that are not covered by any match, rather than the matches
+    gaps: bool,
+    ///Skip matches that begin inside a region enclosed by one of these delimiter pairs
+    skip_inside: Option<DelimiterPairs>,
+    ///Write the suffix array of the text to this file ('-' for standard error)
+    dump_suffix_array: Option<String>,
+    ///Match multi-token lexicon entries with up to this many other tokens between their tokens (--tokens)
+    max_gap: Option<usize>,
+    ///Report entries that occur less often than the frequency threshold on standard error
+    show_below: bool,
+    ///Fail if the coverage over all lexicons and texts is below this ratio
+    min_coverage: Option<f64>,
+    ///Look up tokens in finite state transducers built from the lexicons rather than in hash sets (--tokens)
+    fst: bool,
+    ///Merge matches that overlap or touch into a single span in the verbose output
+    merge_adjacent: bool,
+    ///Tokens are the matches of this regular expression rather than runs of alphanumeric characters (--tokens)
+    token_regex: Option<Regex>,
+    ///Character encoding of the texts and lexicons, they are transcoded to UTF-8 on reading (None for UTF-8 itself)
+    encoding: Option<&'static Encoding>,
+    ///Number of matches output so far (for --limit), shared by all threads
+    output_count: AtomicUsize,
+}
+
+impl Options {
+    fn from_args(args: &ArgMatches) -> Result<Self, LexmatchError> {
+        let strings = |name: &str| -> Vec<String> {
+            args.get_many::<String>(name)
+                .map(|values| values.cloned().collect())
+                .unwrap_or_default()
+        };
+        let string = |name: &str| -> Option<String> { args.value_of(name).map(|s| s.to_string()) };
+        let lexicon_freqs = strings("freq")
+            .iter()
+            .map(|value| {
+                value.parse::<usize>().map_err(|_| {
+                    LexmatchError::Arguments(format!("Invalid value for --freq: {}", value))
+                })
+            })
+            .collect::<Result<Vec<usize>, LexmatchError>>()?;
+        let mut lexicons = strings("lexicon");
+        let mut lexicon_default_names = lexicons.clone();
+        for dir in strings("lexicon-dir") {
+            for (filename, name) in
+                read_lexicon_dir(&dir).map_err(|err| LexmatchError::File(dir.clone(), err))?
+            {
+                lexicons.push(filename);
+                lexicon_default_names.push(name);
+            }
+        }
+        let mut textfiles = strings("textfile");
+        let manifest = string("manifest");
+        if let Some(filename) = manifest.as_deref() {
+            textfiles.extend(
+                read_manifest(filename)
+                    .map_err(|err| LexmatchError::File(filename.to_string(), err))?,
+            );
+        }
+        let options = Self {
+            lexicons,
+            lexicon_names: strings("lexicon-name"),
+            lexicon_default_names,
+            queries: strings("query"),
+            query_file: string("query-file"),
+            stoplist: string("stoplist"),
+            textfiles,
+            output: string("output"),
+            folia: string("folia"),
+            extract: string("extract"),
+            all: args.is_present("all"),
+            no_case: args.is_present("no-case"),
+            ascii_no_case: args.is_present("ascii-no-case"),
+            case_fold: args.is_present("case-fold"),
+            verbose: args.is_present("verbose"),
+            count_only: args.is_present("no-matches"),
+            tokens: args.is_present("tokens"),
+            whole_line: args.is_present("whole-line"),
+            cjk: parse_arg(args, "cjk")?,
+            cjk_min: parse_arg(args, "cjk-min")?.unwrap_or(1),
+            cjk_tile: args.is_present("cjk-tile"),
+            coverage: args.is_present("coverage"),
+            coverage_matrix: args.is_present("coverage-matrix"),
+            freq: lexicon_freqs.last().copied().unwrap_or(1),
+            lexicon_freqs,
+            max_freq: parse_arg(args, "max-freq")?,
+            min_token_length: parse_arg(args, "min-token-length")?.unwrap_or(1),
+            max_matches: parse_arg(args, "max-matches")?,
+            line_col: args.is_present("line-col"),
+            no_cross_newline: args.is_present("no-cross-newline"),
+            sort: args.is_present("sort"),
+            oov: args.is_present("oov"),
+            threads: parse_arg(args, "threads")?.unwrap_or(1),
+            aho_corasick: args.is_present("aho-corasick"),
+            stats: args.is_present("stats"),
+            report_misses: args.is_present("report-misses"),
+            distinct_count: args.is_present("distinct-count"),
+            relative: args.is_present("relative"),
+            normalize_newlines: args.is_present("normalize-newlines"),
+            stream: args.is_present("stream"),
+            no_numbers: args.is_present("no-numbers"),
+            stable_columns: args.is_present("stable-columns"),
+            boundary: parse_arg(args, "boundary")?.unwrap_or(Boundary::Alnum),
+            inclusive_end: args.is_present("inclusive-end"),
+            text_column: parse_arg(args, "text-column")?,
+            fast_index: args.is_present("fast-index"),
+            with_length: args.is_present("with-length"),
+            validate_lexicons: args.is_present("validate"),
+            lexicon_hash: args.is_present("lexicon-hash"),
+            longest_only: args.is_present("longest-only"),
+            no_contained: args.is_present("no-contained"),
+            json_grouped: args.is_present("json-grouped"),
+            lossy: args.is_present("lossy"),
+            mark_line_start: args.is_present("mark-line-start"),
+            concat: args.is_present("concat") || manifest.is_some(),
+            benchmark: args.is_present("benchmark"),
+            summary_matrix: args.is_present("summary-matrix"),
+            entry_matrix: args.is_present("entry-matrix"),
+            collapse_whitespace: args.is_present("collapse-whitespace"),
+            fold_diacritics: parse_arg(args, "fold-diacritics")?,
+            coverage_denominator: parse_arg(args, "coverage-denominator")?
+                .unwrap_or(CoverageDenominator::AlphaTokens),
+            bytes: args.is_present("bytes"),
+            no_append_newline: args.is_present("no-append-newline"),
+            conllu: args.is_present("conllu"),
+            first_match_only: args.is_present("first-match-only"),
+            lexicon_ids: args.is_present("lexicon-ids"),
+            alnum_chars: parse_arg(args, "alnum-chars")?,
+            exact_phrase: args.is_present("exact-phrase"),
+            offset_base: parse_arg(args, "offset-base")?.unwrap_or(0),
+            prefix: args.is_present("prefix"),
+            suffix: args.is_present("suffix"),
+            quiet: args.is_present("quiet"),
+            exclude_lexicon: string("exclude-lexicon"),
+            csv_lexicon: args.is_present("csv-lexicon"),
+            group_by_lexicon: args.is_present("group-by-lexicon"),
+            limit: parse_arg(args, "limit")?,
+            header_json: args.is_present("header-json"),
+            positions_only: args.is_present("positions-only"),
+            mark_contained: args.is_present("mark-contained"),
+            skip_bad_lexicons: args.is_present("skip-bad-lexicons"),
+            weighted_coverage: args.is_present("weighted-coverage"),
+            density: args.is_present("density"),
+            gaps: args.is_present("gaps"),
+            skip_inside: parse_arg(args, "skip-inside")?,
+            dump_suffix_array: string("dump-suffix-array"),
+            max_gap: parse_arg(args, "max-gap")?,
+            show_below: args.is_present("show-below"),
+            min_coverage: parse_arg(args, "min-coverage")?,
+            fst: args.is_present("fst"),
+            merge_adjacent: args.is_present("merge-adjacent"),
+            token_regex: args
+                .value_of("token-regex")
+                .map(Regex::new)
+                .transpose()
+                .map_err(|err| {
+                    LexmatchError::Arguments(format!("Invalid value for --token-regex: {}", err))
+                })?,
+            encoding: args
+                .value_of("encoding")
+                .map(|label| {
+                    Encoding::for_label(label.as_bytes()).ok_or_else(|| {
+                        LexmatchError::Arguments(format!(
+                            "Invalid value for --encoding: unknown encoding '{}'",
+                            label
+                        ))
+                    })
+                })
+                .transpose()?
+                //UTF-8 is read as usual, without any transcoding
+                .filter(|encoding| *encoding != encoding_rs::UTF_8),
+            output_count: AtomicUsize::new(0),
+            format: args
+                .value_of("format")
+                .map(|format| format.parse::<Format>())
+                .transpose()
+                .map_err(|err| {
+                    LexmatchError::Arguments(format!("Invalid value for --format: {}", err))
+                })?,
+        };
+        options.validate()?;
+        Ok(options)
+    }
+
+    ///Check for incompatible options
+    fn validate(&self) -> Result<(), LexmatchError> {
+        if self.folia.as_deref() == Some("-") && self.output.as_deref().is_none_or(|f| f == "-") {
+            return Err(LexmatchError::Arguments(
+                "--folia - writes FoLiA to standard output, pass --output to write the other output to a file"
+                    .to_string(),
+            ));
+        }
+
+        if self.lexicon_names.len() > self.lexicons.len() {
+            return Err(LexmatchError::Arguments(
+                "--lexicon-name is given more often than there are lexicons".to_string(),
+            ));
+        }
+
+        if self.validate_lexicons && self.lexicons.is_empty() {
+            return Err(LexmatchError::Arguments(
+                "--validate requires --lexicon or --lexicon-dir".to_string(),
+            ));
+        }
+
+        if self.lexicons.is_empty()
+            && self.queries.is_empty()
+            && self.query_file.is_none()
+            && self.extract.is_none()
+        {
+            return Err(LexmatchError::Arguments(
+                "specify either --lexicon, --lexicon-dir, --query or --query-file".to_string(),
+            ));
+        }
+
+        if self.count_only && self.verbose {
+            return Err(LexmatchError::Arguments(
+                "--count-only and --verbose are mutually exclusive".to_string(),
+            ));
+        }
+
+        if self.count_only && self.linear() && (self.freq != 1 || self.max_freq.is_some()) {
+            return Err(LexmatchError::Arguments(
+                "Frequency thresholds do not work with --count-only in combination with --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+
+        if !self.linear() && self.coverage {
+            return Err(LexmatchError::Arguments(
+                "--coverage can only be used with --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+
+        if (self.linear() || self.verbose) && self.relative {
+            return Err(LexmatchError::Arguments(
+                "--relative can not be used with --verbose, --tokens, --cjk or --whole-line"
+                    .to_string(),
+            ));
+        }
+
+        if self.stream && !self.linear() && !self.coverage_matrix {
+            return Err(LexmatchError::Arguments(
+                "--stream requires --tokens, --cjk, --whole-line or --coverage-matrix".to_string(),
+            ));
+        }
+
+        if let Some(max_freq) = self.max_freq {
+            if self.lexicon_freqs.iter().any(|freq| max_freq < *freq) {
+                return Err(LexmatchError::Arguments(
+                    "--max-freq can not be smaller than --freq".to_string(),
+                ));
+            }
+        }
+
+        if self.stream
+            && (self.lexicon_freqs.iter().any(|freq| *freq != 1) || self.max_freq.is_some())
+        {
+            return Err(LexmatchError::Arguments(
+                "Frequency thresholds do not work with --stream".to_string(),
+            ));
+        }
+
+        if self.lexicon_freqs.len() > 1 {
+            if self.linear() || self.coverage_matrix || self.bytes {
+                return Err(LexmatchError::Arguments(
+                    "A frequency threshold for each lexicon (multiple --freq) can not be used with --tokens, --cjk, --whole-line, --coverage-matrix or --bytes"
+                        .to_string(),
+                ));
+            }
+            if self.skip_bad_lexicons {
+                return Err(LexmatchError::Arguments(
+                    "A frequency threshold for each lexicon (multiple --freq) can not be used with --skip-bad-lexicons"
+                        .to_string(),
+                ));
+            }
+        }
+
+        if self.dump_suffix_array.is_some() {
+            if !cfg!(feature = "debug-dump") {
+                return Err(LexmatchError::Arguments(
+                    "--dump-suffix-array is not available, lexmatch was built without the debug-dump feature"
+                        .to_string(),
+                ));
+            }
+            if self.textfiles.len() > 1 && !self.concat {
+                return Err(LexmatchError::Arguments(
+                    "--dump-suffix-array requires a single text (or --concat)".to_string(),
+                ));
+            }
+        }
+
+        if self.fast_index && !cfg!(feature = "fast-index") {
+            return Err(LexmatchError::Arguments(
+                "--fast-index is not available, lexmatch was built without the fast-index feature"
+                    .to_string(),
+            ));
+        }
+
+        if self.cjk_min == 0 {
+            return Err(LexmatchError::Arguments(
+                "--cjk-min must be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(maxlen) = self.cjk {
+            if self.cjk_min > maxlen {
+                return Err(LexmatchError::Arguments(
+                    "--cjk-min can not be larger than --cjk".to_string(),
+                ));
+            }
+        }
+
+        if self.with_length && !self.verbose_output() && !self.gaps {
+            return Err(LexmatchError::Arguments(
+                "--with-length requires --verbose, --tokens, --cjk, --whole-line or --gaps"
+                    .to_string(),
+            ));
+        }
+
+        if self.longest_only && !self.verbose_output() {
+            return Err(LexmatchError::Arguments(
+                "--longest-only requires --verbose".to_string(),
+            ));
+        }
+
+        if self.json_grouped && (self.verbose_output() || self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--json-grouped can not be used with --verbose, --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.mark_line_start && !self.verbose_output() {
+            return Err(LexmatchError::Arguments(
+                "--mark-line-start requires --verbose, --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+
+        if self.concat && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--concat can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.fold_diacritics.is_some_and(|fold| fold.text())
+            && (self.stream
+                || self.conllu
+                || self.coverage_matrix
+                || self.collapse_whitespace
+                || self.text_column.is_some()
+                || self.folia.is_some()
+                || self.line_col
+                || self.concat
+                || self.density
+                || self.gaps)
+        {
+            return Err(LexmatchError::Arguments(
+                "--fold-diacritics on the text can not be used with --stream, --conllu, --coverage-matrix, --collapse-whitespace, --text-column, --folia, --line-col, --concat, --density or --gaps (fold only the lexicon instead)"
+                    .to_string(),
+            ));
+        }
+
+        if self.collapse_whitespace && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--collapse-whitespace can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.bytes && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--bytes can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.first_match_only && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--first-match-only can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.exact_phrase && (self.all || self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--exact-phrase can not be used with --all, --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.fst && !self.tokens {
+            return Err(LexmatchError::Arguments(
+                "--fst requires --tokens".to_string(),
+            ));
+        }
+
+        if self.prefix
+            && (self.all
+                || self.exact_phrase
+                || (self.linear() && !self.fst)
+                || self.coverage_matrix)
+        {
+            return Err(LexmatchError::Arguments(
+                "--prefix can not be used with --all, --exact-phrase, --tokens (unless with --fst), --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.suffix && (self.all || self.exact_phrase || self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--suffix can not be used with --all, --exact-phrase, --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.header_json && !self.verbose_output() && !self.gaps {
+            return Err(LexmatchError::Arguments(
+                "--header-json requires --verbose, --tokens, --cjk, --whole-line or --gaps"
+                    .to_string(),
+            ));
+        }
+
+        if self.mark_contained && !self.verbose_output() {
+            return Err(LexmatchError::Arguments(
+                "--mark-contained requires --verbose, --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+
+        if self.merge_adjacent && !self.verbose_output() {
+            return Err(LexmatchError::Arguments(
+                "--merge-adjacent requires --verbose, --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+
+        if self.positions_only && !self.verbose_output() && !self.gaps {
+            return Err(LexmatchError::Arguments(
+                "--positions-only requires --verbose, --tokens, --cjk, --whole-line or --gaps"
+                    .to_string(),
+            ));
+        }
+
+        //outside of verbose output, only the rows for each entry in the default (suffix array) mode are counted
+        if self.limit.is_some()
+            && !self.verbose_output()
+            && !self.gaps
+            && (self.linear() || self.coverage_matrix || self.density)
+        {
+            return Err(LexmatchError::Arguments(
+                "--limit can not be used with --coverage-matrix or --density, nor with --count-only or --conllu in --tokens, --cjk or --whole-line mode"
+                    .to_string(),
+            ));
+        }
+
+        if self.limit == Some(0) {
+            return Err(LexmatchError::Arguments(
+                "--limit must be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(format) = self.format.as_ref() {
+            if !self.verbose_output() {
+                return Err(LexmatchError::Arguments(
+                    "--format requires --verbose, --tokens, --cjk or --whole-line".to_string(),
+                ));
+            }
+            if format.has_count() && self.stream {
+                return Err(LexmatchError::Arguments(
+                    "{count} in --format can not be used with --stream".to_string(),
+                ));
+            }
+        }
+
+        if self.exclude_lexicon.is_some() && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--exclude-lexicon can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.skip_inside.is_some() && (self.linear() || self.coverage_matrix) {
+            return Err(LexmatchError::Arguments(
+                "--skip-inside can not be used with --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if self.show_below && self.lexicon_freqs.iter().all(|freq| *freq <= 1) {
+            return Err(LexmatchError::Arguments(
+                "--show-below requires a frequency threshold above 1 (--freq)".to_string(),
+            ));
+        }
+
+        if self.token_regex.is_some() && !self.tokens {
+            return Err(LexmatchError::Arguments(
+                "--token-regex requires --tokens".to_string(),
+            ));
+        }
+
+        if self.max_gap.is_some() && !self.tokens {
+            return Err(LexmatchError::Arguments(
+                "--max-gap requires --tokens".to_string(),
+            ));
+        }
+
+        if self.conllu && !self.tokens {
+            return Err(LexmatchError::Arguments(
+                "--conllu requires --tokens".to_string(),
+            ));
+        }
+
+        if self.text_column == Some(0) {
+            return Err(LexmatchError::Arguments(
+                "--text-column starts counting at 1".to_string(),
+            ));
+        }
+
+        if self.text_column.is_some() && !self.verbose_output() && !self.coverage_matrix {
+            return Err(LexmatchError::Arguments(
+                "--text-column requires --verbose, --tokens, --cjk, --whole-line or --coverage-matrix"
+                    .to_string(),
+            ));
+        }
+
+        if !self.linear() && self.oov {
+            return Err(LexmatchError::Arguments(
+                "--oov can only be used with --tokens, --cjk or --whole-line".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    ///Linear search mode, each token (--tokens), character (--cjk) or line (--whole-line) in the text is looked up in the lexicons
+    fn linear(&self) -> bool {
+        self.tokens || self.cjk.is_some() || self.whole_line
+    }
+
+    ///How differences in case are handled
+    fn case(&self) -> Case {
+        if self.case_fold {
+            Case::Fold
+        } else if self.no_case {
+            Case::Lower
+        } else if self.ascii_no_case {
+            Case::Ascii
+        } else {
+            Case::Sensitive
+        }
+    }
+
+    ///Is the character alphanumeric, i.e. part of a token
+    fn is_alphanumeric(&self, c: char) -> bool {
+        if let Some(alnum_chars) = self.alnum_chars.as_ref() {
+            alnum_chars.contains(c)
+        } else {
+            c.is_alphanumeric()
+        }
+    }
+
+    ///Is the character a boundary for exact matches
+    fn is_boundary(&self, c: char) -> bool {
+        if self.boundary == Boundary::Alnum {
+            !self.is_alphanumeric(c)
+        } else {
+            self.boundary.is_boundary(c)
+        }
+    }
+
+    ///Handle a lexicon that could not be read: report it and continue with the other lexicons (--skip-bad-lexicons) or fail
+    fn skip_bad_lexicon(&self, filename: &str, err: std::io::Error) -> Result<(), LexmatchError> {
+        if self.skip_bad_lexicons {
+            eprintln!("Warning: skipping lexicon {}: {}", filename, err);
+            Ok(())
+        } else {
+            Err(LexmatchError::File(filename.to_string(), err))
+        }
+    }
+
+    ///The frequency threshold for the lexicon with the given index: the --freq at the same position, or the last --freq otherwise (e.g. for --query)
+    fn freq_threshold(&self, lexiconindex: usize) -> usize {
+        self.lexicon_freqs
+            .get(lexiconindex)
+            .copied()
+            .unwrap_or(self.freq)
+    }
+
+    ///Count the matches of each lexicon entry (--entry-matrix, --weighted-coverage)
+    fn track_entrycount(&self) -> bool {
+        self.entry_matrix || self.weighted_coverage
+    }
+
+    ///Count a match that has been output, returns LexmatchError::LimitReached once the --limit is reached
+    fn count_output(&self) -> Result<(), LexmatchError> {
+        if let Some(limit) = self.limit {
+            if self.output_count.fetch_add(1, Ordering::Relaxed) + 1 >= limit {
+                return Err(LexmatchError::LimitReached);
+            }
+        }
+        Ok(())
+    }
+
+    ///The names of the lexicons in the output: the name given with --lexicon-name, or the filename otherwise
+    fn lexicon_labels(&self) -> Vec<String> {
+        self.lexicon_default_names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| self.lexicon_names.get(i).unwrap_or(name).clone())
+            .collect()
+    }
+
+    ///Output each match on a separate row
+    fn verbose_output(&self) -> bool {
+        self.verbose
+            || (self.linear() && !self.count_only && !self.conllu && !self.density && !self.gaps)
+    }
+}
+
+///List the lexicons in a directory (--lexicon-dir): all files ending in .tsv or .txt, optionally followed by .gz, in alphabetical order. Other files are skipped with a warning.
+///Returns the path of each lexicon and its file name (without the directory), which is used as the name of the lexicon.
+fn read_lexicon_dir(dir: &str) -> Result<Vec<(String, String)>, std::io::Error> {
+    let mut filenames = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let filename = path.to_string_lossy().into_owned();
+        let name = filename.strip_suffix(".gz").unwrap_or(&filename);
+        if path.is_file() && (name.ends_with(".tsv") || name.ends_with(".txt")) {
+            let name = path
+                .file_name()
+                .map_or(filename.clone(), |name| name.to_string_lossy().into_owned());
+            filenames.push((filename, name));
+        } else {
+            eprintln!(
+                "Warning: skipping {}, lexicons must be .tsv or .txt files (optionally gzipped)",
+                filename
+            );
+        }
+    }
+    filenames.sort_unstable();
+    Ok(filenames)
+}
+
+///Read the filenames of the texts that make up a single document from a manifest (--manifest), one per line in order. Empty lines are skipped.
+fn read_manifest(filename: &str) -> Result<Vec<String>, std::io::Error> {
+    let mut textfiles = Vec::new();
+    for line in BufReader::new(File::open(filename)?).lines() {
+        let line = line?;
+        let textfile = line.trim();
+        if !textfile.is_empty() {
+            textfiles.push(textfile.to_string());
+        }
+    }
+    Ok(textfiles)
+}
+
+///Open a lexicon file for reading, decompressing it if the filename ends in .gz and transcoding it to UTF-8 if an encoding is given (--encoding)
+fn open_lexicon(
+    filename: &str,
+    encoding: Option<&'static Encoding>,
+) -> Result<Box<dyn Read>, std::io::Error> {
+    let f = File::open(filename)?;
+    let f: Box<dyn Read> = if filename.ends_with(".gz") {
+        Box::new(GzDecoder::new(f))
+    } else {
+        Box::new(f)
+    };
+    transcode_reader(f, encoding)
+}
+
+///Transcode everything that can be read from the reader to UTF-8 (--encoding), returns the reader as-is if no encoding is given
+fn transcode_reader(
+    mut f: Box<dyn Read>,
+    encoding: Option<&'static Encoding>,
+) -> Result<Box<dyn Read>, std::io::Error> {
+    if let Some(encoding) = encoding {
+        let mut bytes: Vec<u8> = Vec::new();
+        f.read_to_end(&mut bytes)?;
+        let (text, _, had_errors) = encoding.decode(&bytes);
+        if had_errors {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("not valid {}", encoding.name()),
+            ));
+        }
+        Ok(Box::new(std::io::Cursor::new(
+            text.into_owned().into_bytes(),
+        )))
+    } else {
+        Ok(f)
+    }
+}
+
+///Iterate over the first two fields (the entry and its value, if any) of each record in a lexicon file, yields None for records that could not be read.
+///Records are lines with tab separated fields, or with `csv` set, tab separated records where fields may be quoted
+///with double quotes (so they can contain tabs and newlines)
+fn lexicon_fields(
+    filename: &str,
+    csv: bool,
+    encoding: Option<&'static Encoding>,
+) -> Result<Box<dyn Iterator<Item = Option<LexiconRecord>>>, std::io::Error> {
+    let f = open_lexicon(filename, encoding)?;
+    if csv {
+        let reader = csv::ReaderBuilder::new()
+            .delimiter(b'\t')
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(f);
+        Ok(Box::new(reader.into_records().map(|record| {
+            record.ok().map(|record| {
+                (
+                    record.get(0).unwrap_or("").to_string(),
+                    record.get(1).map(|value| value.to_string()),
+                )
+            })
+        })))
+    } else {
+        Ok(Box::new(BufReader::new(f).lines().map(|line| {
+            line.ok().map(|line| {
+                let mut fields = line.split('\t');
+                (
+                    fields.next().unwrap().to_string(),
+                    fields.next().map(|value| value.to_string()),
+                )
+            })
+        })))
+    }
+}
+
+///Read a lexicon, one entry per line, TSV is allowed with entry in first column (rest will just be ignored)
+fn read_lexicon(
+    filename: &str,
+    case: Case,
+    csv: bool,
+    encoding: Option<&'static Encoding>,
+) -> Result<Lexicon, std::io::Error> {
+    let mut lexicon = HashSet::new();
+    for (field, _) in lexicon_fields(filename, csv, encoding)?.flatten() {
+        if !field.is_empty() {
+            lexicon.insert(case.convert(field));
+        }
+    }
+    Ok(lexicon)
+}
+
+///Read the weights of the entries of a lexicon from the second column (--weighted-coverage). Entries without a numeric weight are left out (they have weight 1).
+fn read_weights(
+    filename: &str,
+    case: Case,
+    csv: bool,
+    encoding: Option<&'static Encoding>,
+) -> Result<Weights, std::io::Error> {
+    let mut weights = HashMap::new();
+    for (field, value) in lexicon_fields(filename, csv, encoding)?.flatten() {
+        if let Some(weight) = value.and_then(|value| value.trim().parse::<f64>().ok()) {
+            if !field.is_empty() {
+                weights.insert(case.convert(field), weight);
+            }
+        }
+    }
+    Ok(weights)
+}
+
+///Compute a stable hash (XXH3, 64 bits) of a lexicon, independent of the order in which the entries were read (--lexicon-hash)
+fn lexicon_hash(lexicon: &Lexicon) -> u64 {
+    let mut entries: Vec<&String> = lexicon.iter().collect();
+    entries.sort_unstable();
+    let mut hasher = Xxh3::new();
+    for entry in entries {
+        hasher.update(entry.as_bytes());
+        hasher.update(b"\n");
+    }
+    hasher.digest()
+}
+
+///Read a lexicon like read_lexicon does and print a report on it to standard error (--validate)
+fn validate_lexicon(
+    filename: &str,
+    case: Case,
+    csv: bool,
+    encoding: Option<&'static Encoding>,
+) -> Result<(), std::io::Error> {
+    let mut lexicon = HashSet::new();
+    let mut lines = 0;
+    let mut empty = 0;
+    let mut invalid = 0;
+    for field in lexicon_fields(filename, csv, encoding)? {
+        lines += 1;
+        let field = match field {
+            Some((field, _)) => field,
+            None => {
+                invalid += 1;
+                continue;
+            }
+        };
+        if field.is_empty() {
+            empty += 1;
+        } else {
+            lexicon.insert(case.convert(field));
+        }
+    }
+    eprintln!("Lexicon {}:", filename);
+    eprintln!("  Lines: {}", lines);
+    eprintln!("  Entries: {}", lexicon.len());
+    eprintln!(
+        "  Duplicates removed: {}",
+        lines - empty - invalid - lexicon.len()
+    );
+    eprintln!("  Empty lines skipped: {}", empty);
+    eprintln!("  Invalid lines skipped: {}", invalid);
+    if let Some(shortest) = lexicon.iter().min_by_key(|entry| entry.chars().count()) {
+        eprintln!(
+            "  Shortest entry: {} ({} characters)",
+            shortest,
+            shortest.chars().count()
+        );
+    }
+    if let Some(longest) = lexicon.iter().max_by_key(|entry| entry.chars().count()) {
+        eprintln!(
+            "  Longest entry: {} ({} characters)",
+            longest,
+            longest.chars().count()
+        );
+    }
+    Ok(())
+}
+
+///Read queries from file, one per line. Unlike read_lexicon, lines are taken as-is without TSV parsing
+fn read_queries(
+    filename: &str,
+    case: Case,
+    encoding: Option<&'static Encoding>,
+) -> Result<Vec<String>, std::io::Error> {
+    let mut queries = Vec::new();
+    let f = transcode_reader(Box::new(File::open(filename)?), encoding)?;
+    let f_buffer = BufReader::new(f);
+    for line in f_buffer.lines() {
+        let query = line?;
+        if !query.is_empty() {
+            queries.push(case.convert(query));
+        }
+    }
+    Ok(queries)
+}
+
+///Decode UTF-8, replacing invalid sequences with U+FFFD (--lossy). Returns the text and the number of replacements that were made.
+fn decode_lossy(bytes: &[u8]) -> (Cow<'_, str>, usize) {
+    let text = String::from_utf8_lossy(bytes);
+    let replacements = match text {
+        Cow::Borrowed(_) => 0,
+        Cow::Owned(ref decoded) => {
+            //replacement characters that were already in the input don't count
+            decoded.matches('\u{fffd}').count()
+                - bytes
+                    .windows(3)
+                    .filter(|w| *w == "\u{fffd}".as_bytes())
+                    .count()
+        }
+    };
+    (text, replacements)
+}
+
+fn read_text(
+    filename: &str,
+    case: Case,
+    encoding: Option<&'static Encoding>,
+    normalize_newlines: bool,
+    lossy: bool,
+    append_newline: bool,
+) -> Result<String, std::io::Error> {
+    let mut bytes: Vec<u8> = Vec::new();
+    if filename == "-" {
+        stdin().lock().read_to_end(&mut bytes)?;
+    } else {
+        let mut f = File::open(filename)?;
+        f.read_to_end(&mut bytes)?;
+    }
+    let mut text = if let Some(encoding) = encoding {
+        //transcode to UTF-8, this also strips a byte order mark
+        let (text, _, had_errors) = encoding.decode(&bytes);
+        if had_errors {
+            if !lossy {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("text is not valid {}", encoding.name()),
+                ));
+            }
+            eprintln!(
+                "Warning: replaced invalid {} sequence(s) in {}",
+                encoding.name(),
+                filename
+            );
+        }
+        text.into_owned()
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(err) if lossy => {
+                let bytes = err.into_bytes();
+                let (text, replacements) = decode_lossy(&bytes);
+                eprintln!(
+                    "Warning: replaced {} invalid UTF-8 sequence(s) in {}",
+                    replacements, filename
+                );
+                text.into_owned()
+            }
+            Err(err) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, err)),
+        }
+    };
+    if text.starts_with('\u{feff}') {
+        //strip the byte order mark, offsets are relative to the text after it
+        text.drain(..'\u{feff}'.len_utf8());
+    }
+    if normalize_newlines && text.contains('\r') {
+        text = text.replace("\r\n", "\n");
+    }
+    text = case.convert(text);
+    if append_newline {
+        text.push('\n'); //ensure we always end with a newline
+    }
+    Ok(text)
+}
+
+///Read begin/end offset pairs (the first two columns of a TSV file)
+fn read_offsets(filename: &str) -> Result<Vec<(usize, usize)>, std::io::Error> {
+    let mut offsets = Vec::new();
+    let f = File::open(filename)?;
+    let f_buffer = BufReader::new(f);
+    for (i, line) in f_buffer.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        match (
+            fields.next().and_then(|s| s.parse::<usize>().ok()),
+            fields.next().and_then(|s| s.parse::<usize>().ok()),
+        ) {
+            (Some(begin), Some(end)) => offsets.push((begin, end)),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("line {}: expected begin and end offsets", i + 1),
+                ))
+            }
+        }
+    }
+    Ok(offsets)
+}
+
+///Output the text for each of the given offsets (the inverse of normal operation)
+fn extract(out: &mut dyn Write, offsetsfile: &str, options: &Options) -> Result<(), LexmatchError> {
+    let texts = &options.textfiles;
+    eprintln!("Reading offsets from {}...", offsetsfile);
+    let offsets = read_offsets(offsetsfile)
+        .map_err(|err| LexmatchError::File(offsetsfile.to_string(), err))?;
+    for textfile in texts.iter() {
+        eprintln!("Reading text from {}...", textfile);
+        let text = read_text(
+            textfile,
+            Case::Sensitive,
+            options.encoding,
+            options.normalize_newlines,
+            options.lossy,
+            !options.no_append_newline,
+        )
+        .map_err(|err| LexmatchError::File(textfile.to_string(), err))?;
+        for &(begin, end) in offsets.iter() {
+            //internally, end offsets are always exclusive
+            let exclusive_end = if options.inclusive_end { end + 1 } else { end };
+            if begin > exclusive_end
+                || exclusive_end > text.len()
+                || !text.is_char_boundary(begin)
+                || !text.is_char_boundary(exclusive_end)
+            {
+                return Err(LexmatchError::File(
+                    textfile.to_string(),
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!(
+                            "offsets {}-{} are not valid UTF-8 boundaries in this text",
+                            begin, end
+                        ),
+                    ),
+                ));
+            }
+            write!(out, "{}", &text[begin..exclusive_end])?;
+            if texts.len() > 1 || options.stable_columns {
+                write!(out, "\t{}", textfile)?;
+            }
+            writeln!(out, "\t{}\t{}", begin, end)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+///Read a lexicon as raw bytes (--bytes), one entry per line, TSV is allowed with entry in first column (rest will just be ignored)
+fn read_byte_lexicon(filename: &str, lowercase: bool) -> Result<HashSet<Vec<u8>>, std::io::Error> {
+    let mut lexicon = HashSet::new();
+    let mut f_buffer = BufReader::new(open_lexicon(filename, None)?);
+    let mut line: Vec<u8> = Vec::new();
+    while f_buffer.read_until(b'\n', &mut line)? > 0 {
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        let field = line.split(|b| *b == b'\t').next().unwrap();
+        if !field.is_empty() {
+            lexicon.insert(if lowercase {
+                field.to_ascii_lowercase()
+            } else {
+                field.to_vec()
+            });
+        }
+        line.clear();
+    }
+    Ok(lexicon)
+}
+
+///Match the lexicons against the raw bytes of the texts (--bytes), rather than against UTF-8 text.
+///This uses an Aho-Corasick automaton over the bytes as the suffix array implementation only supports UTF-8.
+fn process_bytes(out: &mut dyn Write, options: &Options) -> Result<(), LexmatchError> {
+    //only ASCII letters are lowercased (--no-case or --ascii-no-case)
+    let lowercase = options.case() != Case::Sensitive;
+    let mut lexicons: Vec<HashSet<Vec<u8>>> = Vec::new();
+    let mut lexiconnames: Vec<String> = Vec::new();
+    for (filename, lexiconname) in options.lexicons.iter().zip(options.lexicon_labels()) {
+        eprintln!("Reading lexicon from {}...", filename);
+        match read_byte_lexicon(filename, lowercase) {
+            Ok(lexicon) => {
+                lexicons.push(lexicon);
+                lexiconnames.push(lexiconname);
+            }
+            Err(err) => options.skip_bad_lexicon(filename, err)?,
+        }
+    }
+    if lexicons.is_empty() && !options.lexicons.is_empty() {
+        return Err(LexmatchError::NoLexicons);
+    }
+    if !options.queries.is_empty() || options.query_file.is_some() {
+        let mut querylexicon: HashSet<Vec<u8>> = options
+            .queries
+            .iter()
+            .map(|query| query.as_bytes().to_vec())
+            .collect();
+        if let Some(filename) = options.query_file.as_deref() {
+            eprintln!("Reading queries from {}...", filename);
+            let queries = read_queries(filename, Case::Sensitive, None)
+                .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+            querylexicon.extend(queries.into_iter().map(|query| query.into_bytes()));
+        }
+        if lowercase {
+            querylexicon = querylexicon
+                .into_iter()
+                .map(|query| query.to_ascii_lowercase())
+                .collect();
+        }
+        lexicons.push(querylexicon);
+        lexiconnames.push("query".to_string());
+    }
+    if let Some(filename) = options.stoplist.as_deref() {
+        eprintln!("Reading stoplist from {}...", filename);
+        let stoplist = read_byte_lexicon(filename, lowercase)
+            .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+        for lexicon in lexicons.iter_mut() {
+            lexicon.retain(|entry| !stoplist.contains(entry));
+        }
+    }
+
+    let texts = &options.textfiles;
+    let lexiconcolumn = lexicons.len() > 1 || options.stable_columns;
+    let resourcecolumn = texts.len() > 1 || options.stable_columns;
+    if options.verbose {
+        write!(out, "Text")?;
+        if lexiconcolumn {
+            write!(out, "\tLexicon")?;
+        }
+        if resourcecolumn {
+            write!(out, "\tResource")?;
+        }
+        if options.inclusive_end {
+            writeln!(out, "\tBeginByteOffset\tInclusiveEndByteOffset")?;
+        } else {
+            writeln!(out, "\tBeginByteOffset\tEndByteOffset")?;
+        }
+    }
+
+    let mut found = false;
+    for textfile in texts.iter() {
+        eprintln!("Reading bytes from {}...", textfile);
+        let mut text: Vec<u8> = Vec::new();
+        if textfile == "-" {
+            stdin().lock().read_to_end(&mut text)
+        } else {
+            File::open(textfile).and_then(|mut f| f.read_to_end(&mut text))
+        }
+        .map_err(|err| LexmatchError::File(textfile.to_string(), err))?;
+        if lowercase {
+            text.make_ascii_lowercase();
+        }
+        if text.len() > u32::MAX as usize {
+            return Err(LexmatchError::Arguments(format!(
+                "{} is too large for --bytes (max 4GB)",
+                textfile
+            )));
+        }
+        eprintln!("Building Aho-Corasick automaton and scanning bytes...");
+        for (lexicon, lexiconname) in lexicons.iter().zip(lexiconnames.iter()) {
+            let patterns: Vec<&[u8]> = lexicon.iter().map(|entry| entry.as_slice()).collect();
+            if patterns.is_empty() {
+                continue;
+            }
+            let automaton = AhoCorasick::new(&patterns).expect("Building Aho-Corasick automaton");
+            let mut positions: Vec<Vec<u32>> = vec![Vec::new(); patterns.len()];
+            for m in automaton.find_overlapping_iter(&text) {
+                //for exact matches, the bytes directly before and after the match may not be ASCII alphanumeric
+                if options.all
+                    || ((m.start() == 0 || !text[m.start() - 1].is_ascii_alphanumeric())
+                        && (m.end() == text.len() || !text[m.end()].is_ascii_alphanumeric()))
+                {
+                    positions[m.pattern().as_usize()].push(m.start() as u32);
+                }
+            }
+            for (pattern, matches) in patterns.iter().zip(positions.iter()) {
+                if matches.len() < options.freq
+                    || matches.len() > options.max_freq.unwrap_or(usize::MAX)
+                {
+                    continue;
+                }
+                found |= !matches.is_empty();
+                //invalid UTF-8 in the matched bytes is output with replacement characters
+                let match_text = String::from_utf8_lossy(pattern);
+                if options.verbose {
+                    for begin in matches.iter() {
+                        let end = *begin as usize + pattern.len();
+                        write!(out, "{}", match_text)?;
+                        if lexiconcolumn {
+                            write!(out, "\t{}", lexiconname)?;
+                        }
+                        if resourcecolumn {
+                            write!(out, "\t{}", textfile)?;
+                        }
+                        writeln!(
+                            out,
+                            "\t{}\t{}",
+                            options.offset_base + *begin as u64,
+                            options.offset_base
+                                + (if options.inclusive_end { end - 1 } else { end }) as u64
+                        )?;
+                    }
+                } else {
+                    print_multi_match(
+                        out,
+                        &match_text,
+                        matches,
+                        if lexiconcolumn {
+                            Some(lexiconname.as_str())
+                        } else {
+                            None
+                        },
+                        if resourcecolumn {
+                            Some(textfile.as_str())
+                        } else {
+                            None
+                        },
+                        None,
+                        options,
+                    )?;
+                }
+            }
+        }
+    }
+    out.flush()?;
+    if !found {
+        return Err(LexmatchError::NoMatches);
+    }
+    Ok(())
+}
+
+fn build_suffixarray(text: &str) -> SuffixTable {
+    SuffixTable::new(text)
+}
+
+///Find all (possibly overlapping) occurrences of all lexicon entries in a single pass over the text, using an Aho-Corasick automaton
+fn build_aho_corasick_index<'a>(
+    text: &str,
+    lexicons: impl IntoIterator<Item = &'a Lexicon>,
+) -> HashMap<&'a str, Vec<u32>> {
+    let patterns: Vec<&str> = lexicons
+        .into_iter()
+        .flat_map(|lexicon| lexicon.iter().map(|entry| entry.as_str()))
+        .filter(|entry| !entry.is_empty())
+        .collect::<HashSet<&str>>()
+        .into_iter()
+        .collect();
+    let automaton = AhoCorasick::new(&patterns).expect("Building Aho-Corasick automaton");
+    let mut positions: HashMap<&str, Vec<u32>> = HashMap::new();
+    for m in automaton.find_overlapping_iter(text) {
+        positions
+            .entry(patterns[m.pattern().as_usize()])
+            .or_default()
+            .push(m.start() as u32);
+    }
+    positions
+}
+
+///Number of bytes of each suffix that is shown by --dump-suffix-array
+const DUMP_SUFFIX_LENGTH: usize = 40;
+
+///Write the suffix array to a file or standard error ('-') for debugging (--dump-suffix-array): a line for each suffix in sorted order
+///with its begin offset (in bytes) and the start of the suffix, with special characters escaped.
+fn dump_suffix_array(filename: &str, text: &str, table: &[u32]) -> Result<(), std::io::Error> {
+    let mut out: Box<dyn Write> = if filename == "-" {
+        Box::new(BufWriter::new(stderr().lock()))
+    } else {
+        Box::new(BufWriter::new(File::create(filename)?))
+    };
+    let bytes = text.as_bytes();
+    for begin in table.iter() {
+        let begin = *begin as usize;
+        //the suffix may start or be cut off in the middle of a multibyte character
+        let suffix =
+            String::from_utf8_lossy(&bytes[begin..(begin + DUMP_SUFFIX_LENGTH).min(bytes.len())]);
+        writeln!(out, "{}\t{}", begin, suffix.escape_debug())?;
+    }
+    out.flush()
+}
+
+#[cfg(feature = "fast-index")]
+fn build_fast_index(text: &str) -> Result<Index<'_>, LexmatchError> {
+    suffixarray::SuffixArray::new(text)
+        .map(Index::FastSuffixArray)
+        .map_err(|err| LexmatchError::Arguments(format!("--fast-index: {}", err)))
+}
+
+#[cfg(not(feature = "fast-index"))]
+fn build_fast_index(_text: &str) -> Result<Index<'_>, LexmatchError> {
+    unreachable!("--fast-index is rejected when the fast-index feature is not enabled")
+}
+
+///An index that is used to look up the positions of lexicon entries in a text
+enum Index<'a> {
+    SuffixArray(SuffixTable<'a, 'a>),
+    #[cfg(feature = "fast-index")]
+    FastSuffixArray(suffixarray::SuffixArray<'a>),
+    AhoCorasick(HashMap<&'a str, Vec<u32>>),
+}
+
+impl Index<'_> {
+    ///Returns the begin offsets of all occurrences of the entry in the text
+    fn positions(&self, entry: &str) -> &[u32] {
+        match self {
+            Self::SuffixArray(suffixtable) => suffixtable.positions(entry),
+            #[cfg(feature = "fast-index")]
+            Self::FastSuffixArray(suffixarray) => suffixarray.positions(entry),
+            Self::AhoCorasick(positions) => positions
+                .get(entry)
+                .map(|positions| positions.as_slice())
+                .unwrap_or(&[]),
+        }
+    }
+}
+
+///Compute the byte offsets at which each line in the text starts
+fn build_line_starts(text: &str) -> Vec<usize> {
+    let mut line_starts = vec![0];
+    for (i, c) in text.char_indices() {
+        if c == '\n' {
+            line_starts.push(i + 1);
+        }
+    }
+    line_starts
+}
+
+///Returns true if the byte offset is at the start of a line, i.e. at the start of the text or right after a newline
+fn is_line_start(text: &str, offset: usize) -> bool {
+    offset == 0 || text.as_bytes()[offset - 1] == b'\n'
+}
+
+///Resolve a byte offset to a 1-based line number and 1-based column (in characters), using the precomputed line starts
+fn line_col(text: &str, line_starts: &[usize], offset: usize) -> (usize, usize) {
+    let line = line_starts.partition_point(|&start| start <= offset);
+    let column = text[line_starts[line - 1]..offset].chars().count() + 1;
+    (line, column)
+}
+
+///A single match of a lexicon entry in the text. If an entry occurs in multiple lexicons, there is one match for each.
+///Serializable if the `serde` feature is enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+struct Match {
+    ///The matched text
+    text: String,
+    ///Begin offset (UTF-8 bytes)
+    begin: usize,
+    ///End offset (UTF-8 bytes, non-inclusive)
+    end: usize,
+    ///The name of the lexicon
+    lexicon: String,
+}
+
+///A match that is buffered (rather than printed immediately) so output can be sorted
+struct VerboseMatch {
+    text: String,
+    begin: usize,
+    end: usize,
+    matched_lexicons: Vec<bool>,
+}
+
+impl VerboseMatch {
+    ///Key to sort on: begin offset, end offset, and index of the (first) matching lexicon
+    fn sortkey(&self) -> (usize, usize, Option<usize>) {
+        (
+            self.begin,
+            self.end,
+            self.matched_lexicons.iter().position(|matches| *matches),
+        )
+    }
+
+    ///Split into a Match for each matching lexicon
+    fn to_matches<'a>(&'a self, lexiconnames: &'a [String]) -> impl Iterator<Item = Match> + 'a {
+        self.matched_lexicons
+            .iter()
+            .zip(lexiconnames.iter())
+            .filter(|(matches, _)| **matches)
+            .map(|(_, lexiconname)| Match {
+                text: self.text.clone(),
+                begin: self.begin,
+                end: self.end,
+                lexicon: lexiconname.clone(),
+            })
+    }
+}
+
+///Keep only the longest matches among those that start at the same position (--longest-only). If no_contained is set, also drop matches that are fully contained in a longer match that starts earlier.
+fn retain_longest(matches: &mut Vec<VerboseMatch>, no_contained: bool) {
+    //longest first for each begin offset
+    matches.sort_unstable_by_key(|m| (m.begin, std::cmp::Reverse(m.end)));
+    let mut begin = None;
+    let mut longest_end = 0; //end of the longest match at the current begin offset
+    let mut covered_until = 0; //end of the longest match at any earlier begin offset
+    matches.retain(|m| {
+        if begin != Some(m.begin) {
+            covered_until = covered_until.max(longest_end);
+            begin = Some(m.begin);
+            longest_end = m.end;
+        }
+        m.end == longest_end && (!no_contained || m.end > covered_until)
+    });
+}
+
+///Print the fraction of the characters of each line that is covered by any of the matches (--density), as TSV.
+///The line terminator does not count as a character of the line; empty lines have a density of 0.
+fn print_density(
+    out: &mut dyn Write,
+    text: &str,
+    matches: &[VerboseMatch],
+    resource: Option<&str>,
+) -> Result<(), std::io::Error> {
+    let mut covered = vec![false; text.len()];
+    for m in matches.iter() {
+        covered[m.begin..m.end].fill(true);
+    }
+    let mut linebegin = 0;
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        let content = line.trim_end_matches(['\n', '\r']);
+        let total = content.chars().count();
+        let matched = content
+            .char_indices()
+            .filter(|(k, _)| covered[linebegin + k])
+            .count();
+        if let Some(resource) = resource {
+            write!(out, "{}\t", resource)?;
+        }
+        writeln!(
+            out,
+            "{}\t{}",
+            i + 1,
+            if total == 0 {
+                0.0
+            } else {
+                matched as f64 / total as f64
+            }
+        )?;
+        linebegin += line.len();
+    }
+    Ok(())
+}
+
+///Merge matches that overlap or touch into maximal spans (--merge-adjacent), a span matches all lexicons that any of its matches matched.
+///The text of a span is taken from the text. Also returns the distinct matched texts that each span consists of, delimited by a semicolon.
+///The matches must be sorted by begin offset.
+fn merge_adjacent(text: &str, matches: Vec<VerboseMatch>) -> (Vec<VerboseMatch>, Vec<String>) {
+    let mut merged: Vec<VerboseMatch> = Vec::new();
+    let mut entries: Vec<Vec<String>> = Vec::new();
+    for m in matches {
+        match (merged.last_mut(), entries.last_mut()) {
+            (Some(last), Some(lastentries)) if m.begin <= last.end => {
+                last.end = last.end.max(m.end);
+                for (matches, othermatches) in
+                    last.matched_lexicons.iter_mut().zip(m.matched_lexicons)
+                {
+                    *matches |= othermatches;
+                }
+                if !lastentries.contains(&m.text) {
+                    lastentries.push(m.text);
+                }
+            }
+            _ => {
+                entries.push(vec![m.text.clone()]);
+                merged.push(m);
+            }
+        }
+    }
+    for m in merged.iter_mut() {
+        m.text = text[m.begin..m.end].to_string();
+    }
+    (
+        merged,
+        entries
+            .into_iter()
+            .map(|entries| entries.join(";"))
+            .collect(),
+    )
+}
+
+///Print the stretches of the text that are not covered by any of the matches (--gaps), like verbose matches.
+///Gaps do not span multiple lines, leading and trailing whitespace is not part of a gap, so gaps of only whitespace are not output.
+///The matches must be sorted by begin offset.
+fn print_gaps(
+    out: &mut dyn Write,
+    text: &str,
+    matches: &[VerboseMatch],
+    resource: Option<&str>,
+    options: &Options,
+) -> Result<(), LexmatchError> {
+    let mut gapbegin = 0;
+    for (begin, end) in matches
+        .iter()
+        .map(|m| (m.begin, m.end))
+        .chain(std::iter::once((text.len(), text.len())))
+    {
+        if begin > gapbegin {
+            let mut linebegin = gapbegin;
+            for line in text[gapbegin..begin].split('\n') {
+                let trimmed = line.trim_start();
+                let gapstart = linebegin + line.len() - trimmed.len();
+                let trimmed = trimmed.trim_end();
+                linebegin += line.len() + 1;
+                if !trimmed.is_empty() {
+                    print_verbose_match(
+                        out,
+                        &VerboseRow {
+                            text: trimmed,
+                            begin: gapstart as u64,
+                            end: (gapstart + trimmed.len()) as u64,
+                            resource,
+                            ..Default::default()
+                        },
+                        options,
+                    )?;
+                    options.count_output()?;
+                }
+            }
+        }
+        gapbegin = gapbegin.max(end);
+    }
+    Ok(())
+}
+
+///Determine for each match whether it is fully contained in a longer match (--mark-contained). The matches must be sorted by begin offset.
+fn contained_matches(matches: &[VerboseMatch]) -> Vec<bool> {
+    let mut contained = Vec::with_capacity(matches.len());
+    let mut covered_until = 0; //end of the longest match at any earlier begin offset
+    let mut i = 0;
+    while i < matches.len() {
+        //matches that start at the same position
+        let len = matches[i..]
+            .iter()
+            .take_while(|m| m.begin == matches[i].begin)
+            .count();
+        let group = &matches[i..i + len];
+        let longest_end = group.iter().map(|m| m.end).max().unwrap_or(0);
+        for m in group.iter() {
+            contained.push(m.end < longest_end || m.end <= covered_until);
+        }
+        covered_until = covered_until.max(longest_end);
+        i += group.len();
+    }
+    contained
+}
+
+///A single row of verbose output, the columns that are output in addition depend on the options (see print_verbose_match)
+#[derive(Default)]
+struct VerboseRow<'a> {
+    ///The matched text
+    text: &'a str,
+    ///Begin offset (UTF-8 bytes)
+    begin: u64,
+    ///End offset (UTF-8 bytes, non-inclusive)
+    end: u64,
+    ///The lexicons the text matched, indices correspond to the lexicon
+    matched_lexicons: &'a [bool],
+    ///The names of the lexicons, if there is a lexicon column
+    lexiconnames: Option<&'a [String]>,
+    ///The text file, if there is a resource column
+    resource: Option<&'a str>,
+    ///Line and column (--line-col)
+    linecol: Option<(usize, usize)>,
+    ///Whether the match is at the start of a line (--mark-line-start)
+    line_start: Option<bool>,
+    ///Whether the match is contained in a longer match (--mark-contained)
+    contained: Option<bool>,
+    ///Further tab separated columns to output at the end (--text-column, --merge-adjacent)
+    extra_columns: Option<&'a str>,
+    ///Number of occurrences of the match (for {count} in --format)
+    count: Option<usize>,
+}
+
+#[inline]
+fn print_verbose_match(
+    out: &mut dyn Write,
+    row: &VerboseRow,
+    options: &Options,
+) -> Result<(), std::io::Error> {
+    let match_text = row.text;
+    let inclusive_end = options.inclusive_end;
+    let offset_base = options.offset_base;
+    let write_lexicons =
+        |out: &mut dyn Write, lexiconnames: &[String]| -> Result<(), std::io::Error> {
+            let mut first = true;
+            for (matches, lexiconname) in row.matched_lexicons.iter().zip(lexiconnames.iter()) {
+                if *matches {
+                    write!(out, "{}{}", if !first { ";" } else { "" }, lexiconname)?;
+                    first = false;
+                }
+            }
+            Ok(())
+        };
+    if let Some(format) = options.format.as_ref() {
+        for part in format.0.iter() {
+            match part {
+                FormatPart::Literal(literal) => write!(out, "{}", literal)?,
+                FormatPart::Text => write!(out, "{}", match_text)?,
+                FormatPart::Begin => write!(out, "{}", offset_base + row.begin)?,
+                FormatPart::End => write!(
+                    out,
+                    "{}",
+                    offset_base + if inclusive_end { row.end - 1 } else { row.end }
+                )?,
+                FormatPart::Lexicon => write_lexicons(out, row.lexiconnames.unwrap_or_default())?,
+                FormatPart::File => write!(out, "{}", row.resource.unwrap_or_default())?,
+                FormatPart::Count => write!(out, "{}", row.count.unwrap_or_default())?,
+            }
+        }
+        return writeln!(out);
+    }
+    let begin = offset_base + row.begin;
+    let end = offset_base + if inclusive_end { row.end - 1 } else { row.end };
+    //without the text (--positions-only), the offsets come first, followed by the lexicon and resource
+    let with_text = !options.positions_only;
+    if with_text {
+        write!(out, "{}", match_text)?;
+    } else {
+        write!(out, "{}\t{}", begin, end)?;
+    }
+    if let Some(lexiconnames) = row.lexiconnames {
+        write!(out, "\t")?;
+        write_lexicons(out, lexiconnames)?;
+    }
+    if let Some(resource) = row.resource {
+        write!(out, "\t{}", resource)?;
+    }
+    if with_text {
+        write!(out, "\t{}\t{}", begin, end)?;
+    }
+    if let Some((line, column)) = row.linecol {
+        write!(out, "\t{}\t{}", line, column)?;
+    }
+    if let Some(line_start) = row.line_start {
+        write!(out, "\t{}", line_start)?;
+    }
+    if let Some(contained) = row.contained {
+        write!(out, "\t{}", contained)?;
+    }
+    if options.with_length {
+        write!(out, "\t{}", match_text.chars().count())?;
+    }
+    if let Some(extra_columns) = row.extra_columns {
+        write!(out, "\t{}", extra_columns)?;
+    }
+    writeln!(out)
+}
+
+///Returns the match offsets to output with --max-matches: the earliest ones in the text rather than the first ones in suffix array order
+fn limit_positions<'a>(indices: &'a [u32], options: &Options) -> Cow<'a, [u32]> {
+    match options.max_matches {
+        Some(max_matches) if max_matches < indices.len() => {
+            let mut indices = indices.to_vec();
+            indices.sort_unstable();
+            indices.truncate(max_matches);
+            Cow::Owned(indices)
+        }
+        _ => Cow::Borrowed(indices),
+    }
+}
+
+#[inline]
+fn print_multi_match(
+    out: &mut dyn Write,
+    match_text: &str,
+    indices: &[u32],
+    lexiconname: Option<&str>,
+    resource: Option<&str>,
+    token_count: Option<usize>,
+    options: &Options,
+) -> Result<(), std::io::Error> {
+    write!(out, "{}", match_text)?;
+    write!(out, "\t{}", indices.len())?;
+    if let Some(token_count) = token_count {
+        //relative frequency
+        if token_count == 0 {
+            write!(out, "\t0")?;
+        } else {
+            write!(out, "\t{}", indices.len() as f64 / token_count as f64)?;
+        }
+    }
+    if let Some(lexiconname) = lexiconname {
+        write!(out, "\t{}", lexiconname)?;
+    }
+    if let Some(resource) = resource {
+        write!(out, "\t{}", resource)?;
+    }
+    if !options.count_only {
+        //dynamic columns
+        for begin in limit_positions(indices, options).iter() {
+            write!(out, "\t{}", options.offset_base + *begin as u64)?;
+        }
+    }
+    writeln!(out)
+}
+
+///Escape a string for use in a JSON string literal
+fn jsonescape(s: &str) -> Cow<'_, str> {
+    if s.contains(|c: char| c == '"' || c == '\\' || c.is_control()) {
+        let mut escaped = String::with_capacity(s.len() + 8);
+        for c in s.chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                '\t' => escaped.push_str("\\t"),
+                c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+                c => escaped.push(c),
+            }
+        }
+        Cow::Owned(escaped)
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+///Print all matches of a lexicon entry as a single JSON object on one line (--json-grouped), the JSON counterpart of print_multi_match
+fn print_json_grouped(
+    out: &mut dyn Write,
+    match_text: &str,
+    indices: &[u32],
+    lexiconname: &str,
+    resource: &str,
+    token_count: Option<usize>,
+    options: &Options,
+) -> Result<(), std::io::Error> {
+    write!(
+        out,
+        "{{\"entry\": \"{}\", \"lexicon\": \"{}\", \"resource\": \"{}\", \"count\": {}",
+        jsonescape(match_text),
+        jsonescape(lexiconname),
+        jsonescape(resource),
+        indices.len()
+    )?;
+    if let Some(token_count) = token_count {
+        write!(
+            out,
+            ", \"relative\": {}",
+            if token_count == 0 {
+                0.0
+            } else {
+                indices.len() as f64 / token_count as f64
+            }
+        )?;
+    }
+    if !options.count_only {
+        write!(out, ", \"positions\": [")?;
+        for (i, begin) in limit_positions(indices, options).iter().enumerate() {
+            write!(
+                out,
+                "{}{}",
+                if i > 0 { ", " } else { "" },
+                options.offset_base + *begin as u64
+            )?;
+        }
+        write!(out, "]")?;
+    }
+    writeln!(out, "}}")
+}
+
+///Returns true if the string is a number, i.e. it has numeric characters but no alphabetic ones. Tokens like these are never considered by --tokens and --coverage-matrix.
+fn is_number(s: &str) -> bool {
+    s.chars().any(|c| c.is_numeric()) && !s.chars().any(|c| c.is_alphabetic())
+}
+
+///Count the tokens in the text, tokens are delimited the same way as with --tokens (and must contain at least one alphabetic character)
+fn count_tokens(text: &str, options: &Options) -> usize {
+    let min_token_length = options.min_token_length;
+    text.split(|c: char| !options.is_alphanumeric(c))
+        .filter(|token| {
+            token.chars().any(|c| c.is_alphabetic())
+                && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+        })
+        .count()
+}
+
+///Count the denominator for the coverage in the text (see --coverage-denominator)
+fn count_denominator(text: &str, options: &Options) -> usize {
+    let min_token_length = options.min_token_length;
+    match options.coverage_denominator {
+        CoverageDenominator::AlphaTokens => count_tokens(text, options),
+        CoverageDenominator::AllTokens => text
+            .split(|c: char| !options.is_alphanumeric(c))
+            .filter(|token| {
+                !token.is_empty()
+                    && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+            })
+            .count(),
+        CoverageDenominator::Chars => count_chars(text),
+    }
+}
+
+///Count the characters of a text or match that the coverage against characters is computed over, i.e. all except whitespace (see --coverage-denominator)
+fn count_chars(text: &str) -> usize {
+    text.chars().filter(|c| !c.is_whitespace()).count()
+}
+
+///Counts that are accumulated over all texts
+struct Counts {
+    ///Number of matches, indices correspond to the lexicon
+    matchcount: Vec<usize>,
+    ///Number of (non-whitespace) characters in the matches, indices correspond to the lexicon (for --coverage-denominator chars)
+    matchedchars: Vec<usize>,
+    ///Total number of tokens/characters considered
+    totalcount: usize,
+    ///Denominator for the coverage if it is not the total count (see --coverage-denominator)
+    denominator: usize,
+    ///Frequency of out-of-vocabulary tokens
+    oov: HashMap<String, usize>,
+    ///Lexicon entries that have been matched at least once, indices correspond to the lexicon (only tracked with --stats or --report-misses)
+    matched_entries: Vec<HashSet<String>>,
+    ///Distinct substrings of the text that matched, indices correspond to the lexicon (only tracked with --distinct-count)
+    matched_forms: Vec<HashSet<String>>,
+    ///Number of matches for each lexicon entry, regardless of the lexicon (only tracked with --entry-matrix)
+    entrycount: HashMap<String, usize>,
+}
+
+impl Counts {
+    fn new(lexicon_count: usize) -> Self {
+        Self {
+            matchcount: vec![0; lexicon_count],
+            matchedchars: vec![0; lexicon_count],
+            totalcount: 0,
+            denominator: 0,
+            oov: HashMap::new(),
+            matched_entries: vec![HashSet::new(); lexicon_count],
+            matched_forms: vec![HashSet::new(); lexicon_count],
+            entrycount: HashMap::new(),
+        }
+    }
+
+    ///The total to compute the coverage against
+    fn coverage_total(&self, denominator: CoverageDenominator) -> usize {
+        if denominator == CoverageDenominator::AlphaTokens {
+            self.totalcount
+        } else {
+            self.denominator
+        }
+    }
+
+    ///What is covered by the matches of a lexicon: the number of matched characters if the coverage is computed against characters, the number of matches otherwise
+    fn coverage_count(&self, j: usize, denominator: CoverageDenominator) -> usize {
+        if denominator == CoverageDenominator::Chars {
+            self.matchedchars[j]
+        } else {
+            self.matchcount[j]
+        }
+    }
+
+    ///The coverage over all lexicons: the number of matches (or matched characters) relative to the denominator (see --coverage-denominator)
+    fn coverage(&self, denominator: CoverageDenominator) -> f64 {
+        let total = self.coverage_total(denominator);
+        if total == 0 {
+            0.0
+        } else {
+            (0..self.matchcount.len())
+                .map(|j| self.coverage_count(j, denominator))
+                .sum::<usize>() as f64
+                / total as f64
+        }
+    }
+
+    ///Add the counts from another (independently processed) text
+    fn merge(&mut self, other: Counts) {
+        for (count, othercount) in self.matchcount.iter_mut().zip(other.matchcount) {
+            *count += othercount;
+        }
+        for (chars, otherchars) in self.matchedchars.iter_mut().zip(other.matchedchars) {
+            *chars += otherchars;
+        }
+        self.totalcount += other.totalcount;
+        self.denominator += other.denominator;
+        for (token, count) in other.oov {
+            *self.oov.entry(token).or_insert(0) += count;
+        }
+        for (entries, otherentries) in self.matched_entries.iter_mut().zip(other.matched_entries) {
+            entries.extend(otherentries);
+        }
+        for (forms, otherforms) in self.matched_forms.iter_mut().zip(other.matched_forms) {
+            forms.extend(otherforms);
+        }
+        for (entry, count) in other.entrycount {
+            *self.entrycount.entry(entry).or_insert(0) += count;
+        }
+    }
+}
+
+///A row of TSV text (see --text-column)
+struct Row {
+    ///Offset of the row in the extracted text
+    begin: usize,
+    ///All other columns, tab separated
+    columns: String,
+}
+
+///Extract a single column (1-based) from TSV text, returns the text of that column (one line per row) and the rows
+fn extract_column(text: &str, column: usize) -> (String, Vec<Row>) {
+    let mut columntext = String::new();
+    let mut rows = Vec::new();
+    for line in text.lines() {
+        let mut columns: Vec<&str> = line.split('\t').collect();
+        let value = if column <= columns.len() {
+            columns.remove(column - 1)
+        } else {
+            ""
+        };
+        rows.push(Row {
+            begin: columntext.len(),
+            columns: columns.join("\t"),
+        });
+        columntext.push_str(value);
+        columntext.push('\n');
+    }
+    (columntext, rows)
+}
+
+///Maps byte offsets in text with collapsed whitespace (see --collapse-whitespace) or folded diacritics (see --fold-diacritics) back to the original text.
+///Holds a pair of offsets (collapsed, original) for each position from which the difference between them changes, e.g. the start of each whitespace run and the character after it.
+struct OffsetMap(Vec<(usize, usize)>);
+
+impl OffsetMap {
+    ///Resolve an offset in the collapsed text to the offset in the original text
+    fn original(&self, offset: usize) -> usize {
+        let (collapsed, original) = self.0[self.0.partition_point(|(c, _)| *c <= offset) - 1];
+        original + (offset - collapsed)
+    }
+}
+
+///Collapse each run of whitespace (including newlines) to a single space, returns the collapsed text and the map back to the original offsets
+fn collapse_whitespace(text: &str) -> (String, OffsetMap) {
+    let mut collapsed = String::with_capacity(text.len());
+    let mut offsetmap = vec![(0, 0)];
+    let mut in_whitespace = false;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                offsetmap.push((collapsed.len(), i));
+                collapsed.push(' ');
+                in_whitespace = true;
+            }
+        } else {
+            if in_whitespace {
+                offsetmap.push((collapsed.len(), i));
+                in_whitespace = false;
+            }
+            collapsed.push(c);
+        }
+    }
+    if in_whitespace {
+        offsetmap.push((collapsed.len(), text.len()));
+    }
+    (collapsed, OffsetMap(offsetmap))
+}
+
+///Remove the diacritics from a string, by decomposing it (NFD) and dropping all combining marks
+fn fold_diacritics(s: &str) -> String {
+    s.nfd().filter(|c| !is_combining_mark(*c)).collect()
+}
+
+///Remove the diacritics from the text (see --fold-diacritics), returns the folded text and the map back to the original offsets
+fn fold_diacritics_text(text: &str) -> (String, OffsetMap) {
+    let mut folded = String::with_capacity(text.len());
+    let mut offsetmap = vec![(0, 0)];
+    let mut buffer = [0; 4];
+    for (i, c) in text.char_indices() {
+        let (lastfolded, lastoriginal) = offsetmap[offsetmap.len() - 1];
+        if lastoriginal + (folded.len() - lastfolded) != i {
+            offsetmap.push((folded.len(), i));
+        }
+        if c.is_ascii() {
+            folded.push(c);
+        } else {
+            folded.push_str(&fold_diacritics(c.encode_utf8(&mut buffer)));
+        }
+    }
+    offsetmap.push((folded.len(), text.len()));
+    (folded, OffsetMap(offsetmap))
+}
+
+///Should a match found in the index be accepted: it must be an exact match (unless --all) and may not span multiple lines (with --no-cross-newline)
+fn accept_match(text: &str, begin: usize, end: usize, options: &Options) -> bool {
+    if options.no_cross_newline && text.as_bytes()[begin..end].contains(&b'\n') {
+        return false;
+    }
+    if options.all {
+        true
+    } else if options.exact_phrase {
+        //the match may not split a token in the text at either end (the tokens inside follow from the exact match)
+        is_token_aligned(text, begin, end, options)
+    } else {
+        //Filter matches that are substrings rather than exact matches,
+        //the characters directly before and after the match must be boundaries (only before with --prefix, only after with --suffix)
+        let before = text[..begin].chars().next_back();
+        let after = text[end..].chars().next();
+        (options.suffix || before.is_none_or(|c| options.is_boundary(c)))
+            && (options.prefix || after.is_none_or(|c| options.is_boundary(c)))
+    }
+}
+
+///Find the spans of the text that match an entry of the exclusion lexicon (--exclude-lexicon). Overlapping spans are merged, so the spans are sorted and disjoint.
+fn find_excluded(
+    text: &str,
+    index: &Index,
+    exclude: &Lexicon,
+    options: &Options,
+) -> Vec<(usize, usize)> {
+    let mut spans: Vec<(usize, usize)> = exclude
+        .iter()
+        .flat_map(|entry| {
+            index
+                .positions(entry)
+                .iter()
+                .map(move |begin| (*begin as usize, *begin as usize + entry.len()))
+        })
+        .filter(|(begin, end)| accept_match(text, *begin, *end, options))
+        .collect();
+    spans.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+    for (begin, end) in spans {
+        match merged.last_mut() {
+            Some(last) if begin <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((begin, end)),
+        }
+    }
+    merged
+}
+
+///Does the span overlap with any of the given sorted and disjoint spans
+fn overlaps(spans: &[(usize, usize)], begin: usize, end: usize) -> bool {
+    let i = spans.partition_point(|(_, spanend)| *spanend <= begin);
+    i < spans.len() && spans[i].0 < end
+}
+
+///Does the span of the text start and end at a token boundary, i.e. it does not split a token of the text (--exact-phrase)
+fn is_token_aligned(text: &str, begin: usize, end: usize, options: &Options) -> bool {
+    let splits_token = |before: Option<char>, after: Option<char>| match (before, after) {
+        (Some(before), Some(after)) => {
+            options.is_alphanumeric(before) && options.is_alphanumeric(after)
+        }
+        _ => false,
+    };
+    let span = &text[begin..end];
+    !splits_token(text[..begin].chars().next_back(), span.chars().next())
+        && !splits_token(span.chars().next_back(), text[end..].chars().next())
+}
+
+///Look up a token in all lexicons, marking in which lexicons it was found and updating the counts. Returns true if it was found in any lexicon.
+///With --no-numbers, a number is never looked up.
+fn lookup_token(
+    token: &str,
+    lexicons: &[Lexicon],
+    fsts: &[FstLexicon],
+    matched_lexicon: &mut [bool],
+    counts: &mut Counts,
+    options: &Options,
+) -> bool {
+    let track_entries = options.stats || options.report_misses;
+    let track_forms = options.distinct_count;
+    let mut has_match = false;
+    let mut chars = None; //counted only once there is a match
+    if options.no_numbers && is_number(token) {
+        //not counted towards the coverage either, like numeric tokens with --tokens
+        return false;
+    }
+    counts.totalcount += 1;
+    for (j, lexicon) in lexicons.iter().enumerate() {
+        matched_lexicon[j] = if fsts.is_empty() {
+            lexicon.contains(token)
+        } else {
+            fsts[j].contains(token)
+        };
+        if matched_lexicon[j] {
+            counts.matchcount[j] += 1;
+            counts.matchedchars[j] += *chars.get_or_insert_with(|| count_chars(token));
+            has_match = true;
+            if track_entries && !counts.matched_entries[j].contains(token) {
+                counts.matched_entries[j].insert(token.to_string());
+            }
+            if track_forms && !counts.matched_forms[j].contains(token) {
+                counts.matched_forms[j].insert(token.to_string());
+            }
+        }
+    }
+    if has_match && options.track_entrycount() {
+        *counts.entrycount.entry(token.to_string()).or_insert(0) += 1;
+    }
+    if !has_match && options.oov {
+        *counts.oov.entry(token.to_string()).or_insert(0) += 1;
+    }
+    has_match
+}
+
+///Look up the longest pattern (in characters, between --cjk-min and --cjk) starting at the given byte offset in all lexicons, marking in which lexicons it was found and updating the counts. Returns the end offset of the match, if any.
+fn lookup_cjk(
+    text: &str,
+    begin: usize,
+    lexicons: &[Lexicon],
+    matched_lexicon: &mut [bool],
+    counts: &mut Counts,
+    options: &Options,
+) -> Option<usize> {
+    let track_entries = options.stats || options.report_misses;
+    let track_forms = options.distinct_count;
+    for l in (options.cjk_min..=options.cjk.unwrap_or(1)).rev() {
+        if let Some((lastbyte, c)) = text[begin..].char_indices().nth(l - 1) {
+            let end = begin + lastbyte + c.len_utf8();
+            let pattern = &text[begin..end];
+            if options.no_numbers && is_number(pattern) {
+                continue;
+            }
+            let mut has_match = false;
+            for (j, lexicon) in lexicons.iter().enumerate() {
+                matched_lexicon[j] = lexicon.contains(pattern);
+                if matched_lexicon[j] {
+                    counts.matchcount[j] += 1;
+                    counts.matchedchars[j] += count_chars(pattern);
+                    has_match = true;
+                    if track_entries && !counts.matched_entries[j].contains(pattern) {
+                        counts.matched_entries[j].insert(pattern.to_string());
+                    }
+                    if track_forms && !counts.matched_forms[j].contains(pattern) {
+                        counts.matched_forms[j].insert(pattern.to_string());
+                    }
+                }
+            }
+            if has_match {
+                if options.track_entrycount() {
+                    *counts.entrycount.entry(pattern.to_string()).or_insert(0) += 1;
+                }
+                return Some(end); //longest match only
+            }
+        }
+    }
+    None
+}
+
+///Called for each match found by scan_tokens() and scan_cjk(), with the matched text, its begin and end offset and the lexicons it matched
+type FoundFn<'a> = dyn FnMut(&str, usize, usize, &[bool]) -> Result<(), LexmatchError> + 'a;
+
+///Look up all tokens in the given range of the text in the lexicons (--tokens), calling found with the text, begin and end offset of each token that matched and the lexicons it matched. The range must start and end at a character that is not alphanumeric (or the start/end of the text).
+///If fsts is not empty (--fst), tokens are looked up in these rather than in the lexicons, which then only need to have the right length.
+fn scan_tokens(
+    text: &str,
+    range: Range<usize>,
+    options: &Options,
+    lexicons: &[Lexicon],
+    fsts: &[FstLexicon],
+    counts: &mut Counts,
+    found: &mut FoundFn<'_>,
+) -> Result<(), LexmatchError> {
+    let min_token_length = options.min_token_length;
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+    let mut lookup = |token: &str, begin: usize| -> Result<(), LexmatchError> {
+        //with --fst and --prefix, the longest lexicon entry the token starts with is looked up instead
+        let key = if options.prefix {
+            longest_prefix(token, fsts).unwrap_or(token)
+        } else {
+            token
+        };
+        if token.chars().any(|c| c.is_alphabetic())
+            && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+            && lookup_token(key, lexicons, fsts, &mut matched_lexicon, counts, options)
+        {
+            found(key, begin, begin + key.len(), &matched_lexicon)?;
+        }
+        Ok(())
+    };
+    if let Some(token_regex) = options.token_regex.as_ref() {
+        for m in token_regex.find_iter(&text[range.clone()]) {
+            if !m.as_str().is_empty() {
+                lookup(m.as_str(), range.start + m.start())?;
+            }
+        }
+        return Ok(());
+    }
+    let mut token = String::new();
+    let mut begin = range.start;
+    //a final newline ensures the last token is also looked up if the text does not end with one (--no-append-newline)
+    for (i, c) in text[range.clone()]
+        .char_indices()
+        .map(|(i, c)| (range.start + i, c))
+        .chain(std::iter::once((range.end, '\n')))
+    {
+        if options.is_alphanumeric(c) {
+            token.push(c);
+        } else if !token.is_empty() {
+            lookup(&token, begin)?;
+            token.clear();
+            begin = i + c.len_utf8();
+        } else {
+            begin = i + c.len_utf8();
+        }
+    }
+    Ok(())
+}
+
+///Build a finite state transducer from a lexicon (--fst), this is more compact than the hash set for large lexicons with many shared prefixes
+fn build_fst(lexicon: &Lexicon) -> FstLexicon {
+    let mut entries: Vec<&String> = lexicon.iter().collect();
+    entries.sort_unstable();
+    FstLexicon::from_iter(entries).expect("entries are sorted and unique")
+}
+
+///Find the longest prefix of the token (possibly the whole token) that is an entry of any of the lexicons (--fst with --prefix)
+fn longest_prefix<'a>(token: &'a str, fsts: &[FstLexicon]) -> Option<&'a str> {
+    token
+        .char_indices()
+        .map(|(i, c)| &token[..i + c.len_utf8()])
+        .rev()
+        .find(|prefix| fsts.iter().any(|fst| fst.contains(prefix)))
+}
+
+///A lexicon entry of multiple tokens (--max-gap)
+struct Phrase {
+    entry: String,
+    tokens: Vec<String>,
+    ///The lexicons the entry occurs in, indices correspond to the lexicon
+    matched_lexicons: Vec<bool>,
+}
+
+///Lexicon entries of multiple tokens, by their first token (--max-gap)
+type Phrases = HashMap<String, Vec<Phrase>>;
+
+///Collect the lexicon entries that consist of multiple tokens (as delimited by --alnum-chars) for --max-gap
+fn build_phrases(lexicons: &[Lexicon], options: &Options) -> Phrases {
+    let mut entries: HashMap<&str, Vec<bool>> = HashMap::new();
+    for (j, lexicon) in lexicons.iter().enumerate() {
+        for entry in lexicon.iter() {
+            entries
+                .entry(entry.as_str())
+                .or_insert_with(|| vec![false; lexicons.len()])[j] = true;
+        }
+    }
+    let mut phrases = Phrases::new();
+    for (entry, matched_lexicons) in entries {
+        let tokens: Vec<String> = entry
+            .split(|c| !options.is_alphanumeric(c))
+            .filter(|token| !token.is_empty())
+            .map(|token| token.to_string())
+            .collect();
+        if tokens.len() > 1 {
+            phrases.entry(tokens[0].clone()).or_default().push(Phrase {
+                entry: entry.to_string(),
+                tokens,
+                matched_lexicons,
+            });
+        }
+    }
+    phrases
+}
+
+///Match the multi-token lexicon entries in the given range of the text (--max-gap), allowing up to --max-gap other tokens between each
+///token of the entry and the next. Each token of the entry is matched with the first candidate, calling found for each match like scan_tokens does,
+///with the lexicon entry as the matched text and the span from the first to the last matched token. The range must start and end at a character that is not alphanumeric.
+fn scan_phrases(
+    text: &str,
+    range: Range<usize>,
+    options: &Options,
+    phrases: &Phrases,
+    counts: &mut Counts,
+    found: &mut FoundFn<'_>,
+) -> Result<(), LexmatchError> {
+    let max_gap = options.max_gap.unwrap_or(0);
+    let track_entries = options.stats || options.report_misses;
+    let track_forms = options.distinct_count;
+    //the begin and end offset of all tokens in the range
+    let mut tokens: Vec<(usize, usize)> = Vec::new();
+    let mut begin = None;
+    for (i, c) in text[range.clone()]
+        .char_indices()
+        .map(|(i, c)| (range.start + i, c))
+        .chain(std::iter::once((range.end, '\n')))
+    {
+        if options.is_alphanumeric(c) {
+            begin.get_or_insert(i);
+        } else if let Some(begin) = begin.take() {
+            tokens.push((begin, i));
+        }
+    }
+    for (i, (begin, end)) in tokens.iter().enumerate() {
+        if let Some(candidates) = phrases.get(&text[*begin..*end]) {
+            for phrase in candidates.iter() {
+                let mut last = Some(i);
+                for token in phrase.tokens[1..].iter() {
+                    last = last.and_then(|last| {
+                        (last + 1..tokens.len().min(last + max_gap + 2))
+                            .find(|k| &text[tokens[*k].0..tokens[*k].1] == token)
+                    });
+                }
+                if let Some(last) = last {
+                    let form = &text[*begin..tokens[last].1];
+                    for (j, matches) in phrase.matched_lexicons.iter().enumerate() {
+                        if *matches {
+                            counts.matchcount[j] += 1;
+                            counts.matchedchars[j] += count_chars(&phrase.entry);
+                            if track_entries && !counts.matched_entries[j].contains(&phrase.entry) {
+                                counts.matched_entries[j].insert(phrase.entry.clone());
+                            }
+                            if track_forms && !counts.matched_forms[j].contains(form) {
+                                counts.matched_forms[j].insert(form.to_string());
+                            }
+                        }
+                    }
+                    if options.track_entrycount() {
+                        *counts.entrycount.entry(phrase.entry.clone()).or_insert(0) += 1;
+                    }
+                    found(
+                        &phrase.entry,
+                        *begin,
+                        tokens[last].1,
+                        &phrase.matched_lexicons,
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+///Greedily look up the longest patterns starting at each character in the given range of the text in the lexicons (--cjk), calling found for each match like scan_tokens does. Patterns may extend beyond the end of the range.
+fn scan_cjk(
+    text: &str,
+    range: Range<usize>,
+    options: &Options,
+    lexicons: &[Lexicon],
+    counts: &mut Counts,
+    found: &mut FoundFn<'_>,
+) -> Result<(), LexmatchError> {
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+    let mut covered_until = range.start;
+    for (begin, c) in text[range.clone()]
+        .char_indices()
+        .map(|(i, c)| (range.start + i, c))
+    {
+        if options.cjk_tile && begin < covered_until {
+            //non-overlapping segmentation: skip past the span of the previous match
+            continue;
+        }
+        let end = lookup_cjk(text, begin, lexicons, &mut matched_lexicon, counts, options);
+        if let Some(end) = end {
+            covered_until = covered_until.max(end);
+            found(&text[begin..end], begin, end, &matched_lexicon)?;
+        } else if options.oov
+            && begin >= covered_until
+            && c.is_alphabetic()
+            && options.min_token_length <= 1
+        {
+            //characters not covered by any match are out-of-vocabulary
+            *counts.oov.entry(c.to_string()).or_insert(0) += 1;
+        }
+    }
+    Ok(())
+}
+
+///Look up each line in the given range of the text in the lexicons as a whole, ignoring leading and trailing whitespace (--whole-line), calling found for each line that matched like scan_tokens does. The range must start at the start of a line.
+fn scan_lines(
+    text: &str,
+    range: Range<usize>,
+    options: &Options,
+    lexicons: &[Lexicon],
+    counts: &mut Counts,
+    found: &mut FoundFn<'_>,
+) -> Result<(), LexmatchError> {
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+    let mut linestart = range.start;
+    for line in text[range].split('\n') {
+        let trimmed = line.trim_start();
+        let begin = linestart + line.len() - trimmed.len();
+        let trimmed = trimmed.trim_end();
+        linestart += line.len() + 1;
+        if !trimmed.is_empty()
+            && lookup_token(
+                trimmed,
+                lexicons,
+                &[],
+                &mut matched_lexicon,
+                counts,
+                options,
+            )
+        {
+            found(trimmed, begin, begin + trimmed.len(), &matched_lexicon)?;
+        }
+    }
+    Ok(())
+}
+
+///Split the text into at most n chunks of about equal size to scan in parallel. Chunks only start at a character for which is_split holds (so that for instance no token is split over two chunks). Returns the byte range of each chunk.
+fn split_chunks(text: &str, n: usize, is_split: impl Fn(char) -> bool) -> Vec<Range<usize>> {
+    let mut chunks = Vec::with_capacity(n);
+    let mut begin = 0;
+    for i in 1..n {
+        let mut end = (text.len() * i / n).max(begin);
+        while !text.is_char_boundary(end) {
+            end += 1;
+        }
+        end = text[end..]
+            .char_indices()
+            .find(|(_, c)| is_split(*c))
+            .map(|(j, _)| end + j)
+            .unwrap_or(text.len());
+        if end > begin {
+            chunks.push(begin..end);
+            begin = end;
+        }
+    }
+    if begin < text.len() || chunks.is_empty() {
+        chunks.push(begin..text.len());
+    }
+    chunks
+}
+
+///Print the header of the --coverage-matrix output
+fn print_coverage_matrix_header(
+    out: &mut dyn Write,
+    lexiconnames: &[String],
+) -> Result<(), std::io::Error> {
+    write!(out, "Line")?;
+    for lexiconname in lexiconnames.iter() {
+        write!(out, "\t{}", lexiconname)?;
+    }
+    if lexiconnames.len() > 1 {
+        write!(out, "\tTotal")?;
+    }
+    writeln!(out)
+}
 
-///Read a lexicon, one entry per line, TSV is allowed with entry in first column (rest will just be ignored)
-fn read_lexicon(filename: &str, lowercase: bool) -> Result<Lexicon, std::io::Error> {
-    let mut lexicon = HashSet::new();
-    let f = File::open(filename)?;
-    let f_buffer = BufReader::new(f);
-    for line in f_buffer.lines() {
-        if let Ok(entry) = line {
-            let field = entry.split("\t").next().unwrap().to_string();
-            if !field.is_empty() {
-                lexicon.insert(if lowercase {
-                    field.to_lowercase()
-                } else {
-                    field
-                });
+///Print a single line of the --coverage-matrix output: the line followed by its coverage for each lexicon
+fn print_coverage_matrix_line(
+    out: &mut dyn Write,
+    line: &str,
+    lexicons: &[Lexicon],
+    lexiconnames: &[String],
+    options: &Options,
+    counts: &mut Counts,
+) -> Result<(), std::io::Error> {
+    let min_token_length = options.min_token_length;
+    let mut token = String::new();
+    counts.totalcount = 0;
+    for item in counts
+        .matchcount
+        .iter_mut()
+        .chain(counts.matchedchars.iter_mut())
+    {
+        //reset matches
+        *item = 0;
+    }
+    write!(out, "{}", line.trim_matches('\r'))?;
+    //chain a newline so the last token of the line is always counted
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if options.is_alphanumeric(c) {
+            token.push(c);
+        } else if !token.is_empty() {
+            if token.chars().any(|c| c.is_alphabetic())
+                && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+            {
+                counts.totalcount += 1;
+                for (j, lexicon) in lexicons.iter().enumerate() {
+                    if lexicon.contains(&token) {
+                        counts.matchcount[j] += 1;
+                        counts.matchedchars[j] += count_chars(&token);
+                    }
+                }
             }
+            token.clear();
         }
     }
-    Ok(lexicon)
+    if options.coverage_denominator != CoverageDenominator::AlphaTokens {
+        counts.denominator = count_denominator(line, options);
+    }
+    let total = counts.coverage_total(options.coverage_denominator);
+    let mut sumcount = 0;
+    for j in 0..counts.matchcount.len() {
+        let count = counts.coverage_count(j, options.coverage_denominator);
+        sumcount += count;
+        write!(
+            out,
+            "\t{}",
+            if total == 0 {
+                0.0
+            } else {
+                count as f64 / total as f64
+            }
+        )?;
+    }
+    if lexiconnames.len() > 1 {
+        write!(
+            out,
+            "\t{}",
+            if total == 0 {
+                0.0
+            } else {
+                sumcount as f64 / total as f64
+            }
+        )?;
+    }
+    writeln!(out)
 }
 
-fn read_text(filename: &str, lowercase: bool) -> Result<String, std::io::Error> {
-    if filename == "-" {
-        let mut text: String = String::new();
-        stdin().lock().read_to_string(&mut text)?;
-        if lowercase {
-            text = text.to_lowercase();
+///Print a single line of the text as a sentence in CoNLL-U format (--conllu): a comment with the line number and the text, then a row for each token with the lexicons it was found in in the MISC column, followed by a blank line. Lines without tokens are skipped.
+fn print_conllu_sentence(
+    out: &mut dyn Write,
+    linenr: usize,
+    line: &str,
+    lexicons: &[Lexicon],
+    lexiconnames: &[String],
+    options: &Options,
+    counts: &mut Counts,
+) -> Result<(), std::io::Error> {
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+    let min_token_length = options.min_token_length;
+    let mut token = String::new();
+    let mut id = 0;
+    //chain a newline so the last token of the line is always handled
+    for c in line.chars().chain(std::iter::once('\n')) {
+        if options.is_alphanumeric(c) {
+            token.push(c);
+        } else if !token.is_empty() {
+            if id == 0 {
+                writeln!(out, "# sent_id = {}", linenr)?;
+                writeln!(out, "# text = {}", line.trim_end_matches('\r'))?;
+            }
+            id += 1;
+            let found = token.chars().any(|c| c.is_alphabetic())
+                && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+                && lookup_token(&token, lexicons, &[], &mut matched_lexicon, counts, options);
+            write!(out, "{}\t{}\t_\t_\t_\t_\t_\t_\t_\t", id, token)?;
+            if found {
+                let names: Vec<&str> = lexiconnames
+                    .iter()
+                    .zip(matched_lexicon.iter())
+                    .filter_map(|(name, matched)| if *matched { Some(name.as_str()) } else { None })
+                    .collect();
+                writeln!(out, "Lexicon={}", names.join(";"))?;
+            } else {
+                writeln!(out, "_")?;
+            }
+            token.clear();
         }
-        text.push('\n'); //ensure we always end with a newline
-        Ok(text)
+    }
+    if id > 0 {
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+///Process a single text in --tokens, --cjk, --whole-line or --coverage-matrix mode without loading it into memory, the text is read line by line and offsets are 64-bit, so there is no limit on the size of the text
+fn process_text_stream(
+    out: &mut dyn Write,
+    textfile: &str,
+    options: &Options,
+    lexicons: &[Lexicon],
+    lexiconnames: &[String],
+    counts: &mut Counts,
+) -> Result<(), LexmatchError> {
+    let min_token_length = options.min_token_length;
+    let do_oov = options.oov;
+    let verbose = options.verbose_output();
+    //with --lexicon-ids, lexicons are referred to by their index rather than their name
+    let lexiconids: Vec<String> = if options.lexicon_ids {
+        (0..lexiconnames.len()).map(|i| i.to_string()).collect()
     } else {
-        let mut f = File::open(filename)?;
-        let mut text: String = String::new();
-        f.read_to_string(&mut text)?;
-        if lowercase {
-            text = text.to_lowercase();
+        Vec::new()
+    };
+    //the lexicon and resource columns are only output if there are multiple, unless --stable-columns is set
+    let lexiconcolumn =
+        if !options.stable_columns && options.format.is_none() && lexiconnames.len() <= 1 {
+            None
+        } else if options.lexicon_ids {
+            Some(lexiconids.as_slice())
+        } else {
+            Some(lexiconnames)
+        };
+    let resource =
+        if options.stable_columns || options.format.is_some() || options.textfiles.len() > 1 {
+            Some(textfile)
+        } else {
+            None
+        };
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+
+    eprintln!("Streaming text from {}...", textfile);
+    if options.coverage_matrix {
+        print_coverage_matrix_header(out, lexiconnames)?;
+    }
+    let mut reader: Box<dyn BufRead> = if textfile == "-" {
+        Box::new(BufReader::new(stdin().lock()))
+    } else {
+        Box::new(BufReader::new(
+            File::open(textfile).map_err(|err| LexmatchError::File(textfile.to_string(), err))?,
+        ))
+    };
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut offset: u64 = 0; //byte offset of the start of the current line
+    let mut linenr = 0;
+    let mut replacements = 0; //number of invalid UTF-8 sequences replaced (--lossy)
+    let mut token = String::new();
+    //for progress reports (--coverage-matrix)
+    let start = Instant::now();
+    let mut bytesread: u64 = 0;
+    let filesize = if textfile == "-" {
+        None
+    } else {
+        std::fs::metadata(textfile)
+            .ok()
+            .map(|metadata| metadata.len())
+    };
+    loop {
+        buffer.clear();
+        if reader
+            .read_until(b'\n', &mut buffer)
+            .map_err(|err| LexmatchError::File(textfile.to_string(), err))?
+            == 0
+        {
+            break;
+        }
+        linenr += 1;
+        bytesread += buffer.len() as u64;
+        //same preprocessing as read_text()
+        let mut line = if options.lossy {
+            let (line, linereplacements) = decode_lossy(&buffer);
+            replacements += linereplacements;
+            line
+        } else {
+            Cow::Borrowed(std::str::from_utf8(&buffer).map_err(|err| {
+                LexmatchError::File(
+                    textfile.to_string(),
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, err),
+                )
+            })?)
+        };
+        if linenr == 1 && line.starts_with('\u{feff}') {
+            line = Cow::Owned(line['\u{feff}'.len_utf8()..].to_string());
+        }
+        if options.normalize_newlines && line.ends_with("\r\n") {
+            line = Cow::Owned(format!("{}\n", &line[..line.len() - 2]));
+        }
+        if options.case() != Case::Sensitive {
+            line = Cow::Owned(options.case().convert(line.into_owned()));
+        }
+        if options.coverage && options.coverage_denominator != CoverageDenominator::AlphaTokens {
+            counts.denominator += count_denominator(&line, options);
+        }
+        if options.coverage_matrix {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            if !line.is_empty() {
+                print_coverage_matrix_line(out, line, lexicons, lexiconnames, options, counts)?;
+            }
+            if !options.quiet && linenr % PROGRESS_INTERVAL == 0 {
+                report_progress(linenr, bytesread, filesize, start);
+            }
+            continue;
+        }
+        if options.conllu {
+            print_conllu_sentence(
+                out,
+                linenr,
+                line.strip_suffix('\n').unwrap_or(&line),
+                lexicons,
+                lexiconnames,
+                options,
+                counts,
+            )?;
+            offset += line.len() as u64;
+            continue;
+        }
+        if options.whole_line {
+            scan_lines(
+                &line,
+                0..line.len(),
+                options,
+                lexicons,
+                counts,
+                &mut |matchtext, begin, end, matched_lexicon| {
+                    if verbose {
+                        print_verbose_match(
+                            out,
+                            &VerboseRow {
+                                text: matchtext,
+                                begin: offset + begin as u64,
+                                end: offset + end as u64,
+                                matched_lexicons: matched_lexicon,
+                                lexiconnames: lexiconcolumn,
+                                resource,
+                                linecol: options
+                                    .line_col
+                                    .then(|| (linenr, line[..begin].chars().count() + 1)),
+                                line_start: options.mark_line_start.then_some(begin == 0),
+                                ..Default::default()
+                            },
+                            options,
+                        )?;
+                        options.count_output()?;
+                    }
+                    Ok(())
+                },
+            )?;
+            offset += line.len() as u64;
+            continue;
+        }
+        if options.cjk.is_some() {
+            //matches never cross a line, so the greedy search can be done per line
+            let mut covered_until = 0;
+            for (begin, c) in line.char_indices() {
+                if options.cjk_tile && begin < covered_until {
+                    continue;
+                }
+                let found = lookup_cjk(
+                    &line,
+                    begin,
+                    lexicons,
+                    &mut matched_lexicon,
+                    counts,
+                    options,
+                );
+                if let Some(end) = found {
+                    covered_until = covered_until.max(end);
+                    if verbose {
+                        print_verbose_match(
+                            out,
+                            &VerboseRow {
+                                text: &line[begin..end],
+                                begin: offset + begin as u64,
+                                end: offset + end as u64,
+                                matched_lexicons: &matched_lexicon,
+                                lexiconnames: lexiconcolumn,
+                                resource,
+                                linecol: options
+                                    .line_col
+                                    .then(|| (linenr, line[..begin].chars().count() + 1)),
+                                line_start: options.mark_line_start.then_some(begin == 0),
+                                ..Default::default()
+                            },
+                            options,
+                        )?;
+                        options.count_output()?;
+                    }
+                } else if do_oov
+                    && begin >= covered_until
+                    && c.is_alphabetic()
+                    && min_token_length <= 1
+                {
+                    *counts.oov.entry(c.to_string()).or_insert(0) += 1;
+                }
+            }
+            offset += line.len() as u64;
+            continue;
+        }
+        let mut begin = 0;
+        //chain a newline so the last token of a line is always handled (the final line may lack one)
+        for (i, c) in line
+            .char_indices()
+            .chain(std::iter::once((line.len(), '\n')))
+        {
+            if options.is_alphanumeric(c) {
+                token.push(c);
+            } else {
+                if !token.is_empty()
+                    && token.chars().any(|c| c.is_alphabetic())
+                    && (min_token_length <= 1 || token.chars().count() >= min_token_length)
+                    && lookup_token(&token, lexicons, &[], &mut matched_lexicon, counts, options)
+                    && verbose
+                {
+                    print_verbose_match(
+                        out,
+                        &VerboseRow {
+                            text: &token,
+                            begin: offset + begin as u64,
+                            end: offset + (begin + token.len()) as u64,
+                            matched_lexicons: &matched_lexicon,
+                            lexiconnames: lexiconcolumn,
+                            resource,
+                            linecol: options
+                                .line_col
+                                .then(|| (linenr, line[..begin].chars().count() + 1)),
+                            line_start: options.mark_line_start.then_some(begin == 0),
+                            ..Default::default()
+                        },
+                        options,
+                    )?;
+                    options.count_output()?;
+                }
+                token.clear();
+                begin = i + c.len_utf8();
+            }
         }
-        text.push('\n');
-        Ok(text)
+        offset += line.len() as u64;
     }
+    if replacements > 0 {
+        eprintln!(
+            "Warning: replaced {} invalid UTF-8 sequence(s) in {}",
+            replacements, textfile
+        );
+    }
+    Ok(())
 }
 
-fn build_suffixarray(text: &str) -> SuffixTable {
-    SuffixTable::new(text)
+///The lexicons that every text is matched against, as loaded once for all texts
+#[derive(Clone, Copy)]
+struct LexiconSet<'a> {
+    lexicons: &'a [Lexicon],
+    ///Finite state transducers built from the lexicons (--fst), empty otherwise
+    fsts: &'a [FstLexicon],
+    ///Names of the lexicons in the output, indices correspond to the lexicon
+    names: &'a [String],
+    ///Lexicon with entries that veto other matches (--exclude-lexicon)
+    exclude: Option<&'a Lexicon>,
 }
 
-#[inline]
-fn print_verbose_match(
-    match_text: &str,
-    begin: usize,
-    end: usize,
-    matched_lexicons: &Vec<bool>,
-    lexiconnames: &Vec<String>,
-    texts_len: usize,
-    textfile: &str,
-) {
-    print!("{}", match_text);
-    if lexiconnames.len() > 1 {
-        print!("\t");
-        let mut first = true;
-        for (matches, lexiconname) in matched_lexicons.iter().zip(lexiconnames.iter()) {
-            if *matches {
-                print!("{}{}", if !first { ";" } else { "" }, lexiconname);
-                first = false;
+///Process a single text, matching it against the lexicons and writing the output
+fn process_text(
+    out: &mut dyn Write,
+    folia: Option<&mut dyn Write>,
+    textindex: usize,
+    options: &Options,
+    lexiconset: LexiconSet,
+    counts: &mut Counts,
+) -> Result<(), LexmatchError> {
+    let LexiconSet {
+        lexicons,
+        fsts,
+        names: lexiconnames,
+        exclude,
+    } = lexiconset;
+    let textfile = options.textfiles[textindex].as_str();
+    let mut phasestart = Instant::now();
+    let phaseresource = if options.concat {
+        "all texts"
+    } else {
+        textfile
+    };
+    if options.stream {
+        process_text_stream(out, textfile, options, lexicons, lexiconnames, counts)?;
+        report_phase(
+            options,
+            "reading text and searching",
+            Some(phaseresource),
+            &mut phasestart,
+        );
+        return Ok(());
+    }
+    let freq_threshold = options.freq;
+    let max_freq = options.max_freq.unwrap_or(usize::MAX);
+    let min_token_length = options.min_token_length;
+    let verbose = options.verbose_output();
+    let do_sort = options.sort;
+    let text_column = options.text_column;
+    //in linear search mode (--tokens/--cjk), the frequency threshold can only be applied after all matches are found
+    let freq_postfilter = (freq_threshold > 1 || options.max_freq.is_some()) && options.linear();
+    //verbose output is deferred until all matches are found if we need to sort or filter them, or need to map offsets back to columns
+    let deferred = do_sort
+        || freq_postfilter
+        || text_column.is_some()
+        || options.longest_only
+        || options.concat
+        || options.collapse_whitespace
+        || options.fold_diacritics.is_some_and(|fold| fold.text())
+        || (options.linear() && options.format.as_ref().is_some_and(Format::has_count))
+        || (options.group_by_lexicon && verbose)
+        || options.mark_contained
+        || options.max_gap.is_some()
+        || options.merge_adjacent;
+    //matches are buffered (rather than only output immediately) if output is deferred or we need them for FoLiA, --density or --gaps output
+    let buffer_matches = deferred || folia.is_some() || options.density || options.gaps;
+    let track_entries = options.stats || options.report_misses;
+    let track_forms = options.distinct_count;
+    //with --lexicon-ids, lexicons are referred to by their index rather than their name
+    let lexiconids: Vec<String> = if options.lexicon_ids {
+        (0..lexiconnames.len()).map(|i| i.to_string()).collect()
+    } else {
+        Vec::new()
+    };
+    //the lexicon and resource columns are only output if there are multiple, unless --stable-columns is set
+    let lexiconcolumn =
+        if !options.stable_columns && options.format.is_none() && lexiconnames.len() <= 1 {
+            None
+        } else if options.lexicon_ids {
+            Some(lexiconids.as_slice())
+        } else {
+            Some(lexiconnames)
+        };
+    let resource =
+        if options.stable_columns || options.format.is_some() || options.textfiles.len() > 1 {
+            Some(textfile)
+        } else {
+            None
+        };
+    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
+
+    let read = |textfile: &str| {
+        eprintln!("Reading text from {}...", textfile);
+        read_text(
+            textfile,
+            options.case(),
+            options.encoding,
+            options.normalize_newlines,
+            options.lossy,
+            !options.no_append_newline,
+        )
+        .map_err(|err| LexmatchError::File(textfile.to_string(), err))
+    };
+    let (text, text_begins) = if options.concat {
+        //all texts are concatenated so a single index is built, we record where each text begins to map matches back
+        let mut text = String::new();
+        let mut text_begins = Vec::with_capacity(options.textfiles.len());
+        for textfile in options.textfiles.iter() {
+            text_begins.push(text.len());
+            text.push_str(&read(textfile)?);
+        }
+        (text, Some(text_begins))
+    } else {
+        (read(textfile)?, None)
+    };
+    //with --fold-diacritics on the text, the matched text is output from the original (unfolded) text
+    let mut unfolded: Option<String> = None;
+    let (text, offsetmap) = if options.collapse_whitespace {
+        let (text, offsetmap) = collapse_whitespace(&text);
+        (text, Some(offsetmap))
+    } else if options.fold_diacritics.is_some_and(|fold| fold.text()) {
+        let (folded, offsetmap) = fold_diacritics_text(&text);
+        unfolded = Some(text);
+        (folded, Some(offsetmap))
+    } else {
+        (text, None)
+    };
+    let (text, rows) = if let Some(column) = text_column {
+        //match only against the given column
+        let (text, rows) = extract_column(&text, column);
+        if textindex == 0 && !options.coverage_matrix {
+            //the other columns are named after their position in the row
+            let width = rows
+                .iter()
+                .map(|row| row.columns.split('\t').count())
+                .max()
+                .unwrap_or(0);
+            let names: Vec<String> = (1..)
+                .filter(|i| *i != column)
+                .take(width)
+                .map(|i| format!("Column{}", i))
+                .collect();
+            let mut columns = verbose_columns(options, lexicons.len(), options.textfiles.len());
+            columns.extend(names.iter().map(String::as_str));
+            print_header(out, &columns, options)?;
+        }
+        (text, Some(rows))
+    } else {
+        (text, None)
+    };
+    let line_starts = if options.line_col {
+        Some(build_line_starts(&text))
+    } else {
+        None
+    };
+    let mut matchbuffer: Vec<VerboseMatch> = Vec::new();
+    if options.coverage && options.coverage_denominator != CoverageDenominator::AlphaTokens {
+        counts.denominator += count_denominator(&text, options);
+    }
+    report_phase(
+        options,
+        "reading text",
+        Some(phaseresource),
+        &mut phasestart,
+    );
+
+    if options.coverage_matrix {
+        print_coverage_matrix_header(out, lexiconnames)?;
+        let start = Instant::now();
+        let mut done = 0;
+        for (i, line) in text.split("\n").enumerate() {
+            if !line.is_empty() {
+                print_coverage_matrix_line(out, line, lexicons, lexiconnames, options, counts)?;
+            }
+            done += line.len() + 1;
+            if !options.quiet && (i + 1) % PROGRESS_INTERVAL == 0 {
+                report_progress(i + 1, done as u64, Some(text.len() as u64), start);
+            }
+        }
+    } else if options.conllu {
+        for (i, line) in text.split('\n').enumerate() {
+            print_conllu_sentence(out, i + 1, line, lexicons, lexiconnames, options, counts)?;
+        }
+    } else if options.linear() {
+        let mut handle = |matchtext: &str,
+                          begin: usize,
+                          end: usize,
+                          matched_lexicon: &[bool]|
+         -> Result<(), LexmatchError> {
+            if buffer_matches {
+                matchbuffer.push(VerboseMatch {
+                    text: matchtext.to_string(),
+                    begin,
+                    end,
+                    matched_lexicons: matched_lexicon.to_vec(),
+                });
+            }
+            if verbose && !deferred {
+                print_verbose_match(
+                    out,
+                    &VerboseRow {
+                        text: matchtext,
+                        begin: begin as u64,
+                        end: end as u64,
+                        matched_lexicons: matched_lexicon,
+                        lexiconnames: lexiconcolumn,
+                        resource,
+                        linecol: line_starts.as_deref().map(|l| line_col(&text, l, begin)),
+                        line_start: options.mark_line_start.then(|| is_line_start(&text, begin)),
+                        ..Default::default()
+                    },
+                    options,
+                )?;
+                options.count_output()?;
+            }
+            Ok(())
+        };
+        let phrases = if options.max_gap.is_some() {
+            build_phrases(lexicons, options)
+        } else {
+            Phrases::new()
+        };
+        let scan = |range: Range<usize>, counts: &mut Counts, found: &mut FoundFn<'_>| {
+            if options.whole_line {
+                scan_lines(&text, range, options, lexicons, counts, found)
+            } else if options.tokens {
+                scan_tokens(&text, range.clone(), options, lexicons, fsts, counts, found)?;
+                if options.max_gap.is_some() {
+                    scan_phrases(&text, range, options, &phrases, counts, found)?;
+                }
+                Ok(())
+            } else {
+                scan_cjk(&text, range, options, lexicons, counts, found)
+            }
+        };
+        //with multiple threads, the text is split into chunks that are scanned in parallel (unless a match depends on the previous one, as with --cjk-tile or --oov in --cjk, or may span multiple tokens, as with --max-gap, or tokens are not delimited by non-alphanumeric characters, as with --token-regex)
+        if options.threads > 1
+            && options.max_gap.is_none()
+            && options.token_regex.is_none()
+            && (options.tokens || !(options.cjk_tile || options.oov))
+        {
+            let chunks = split_chunks(&text, options.threads, |c| {
+                if options.whole_line {
+                    c == '\n'
+                } else {
+                    options.cjk.is_some() || !options.is_alphanumeric(c)
+                }
+            });
+            let results: Vec<(Counts, Vec<VerboseMatch>)> = chunks
+                .into_par_iter()
+                .map(|range| {
+                    let mut chunkcounts = Counts::new(lexicons.len());
+                    let mut chunkmatches = Vec::new();
+                    scan(
+                        range,
+                        &mut chunkcounts,
+                        &mut |matchtext, begin, end, matched_lexicon| {
+                            chunkmatches.push(VerboseMatch {
+                                text: matchtext.to_string(),
+                                begin,
+                                end,
+                                matched_lexicons: matched_lexicon.to_vec(),
+                            });
+                            Ok(())
+                        },
+                    )?;
+                    Ok((chunkcounts, chunkmatches))
+                })
+                .collect::<Result<_, LexmatchError>>()?;
+            //the chunks are in order, so the matches are still handled in reading order
+            for (chunkcounts, chunkmatches) in results {
+                counts.merge(chunkcounts);
+                for m in chunkmatches.iter() {
+                    handle(&m.text, m.begin, m.end, &m.matched_lexicons)?;
+                }
+            }
+        } else {
+            scan(0..text.len(), counts, &mut handle)?;
+        }
+    } else {
+        let index = if options.aho_corasick {
+            eprintln!("Building Aho-Corasick automaton and scanning text...");
+            Index::AhoCorasick(build_aho_corasick_index(
+                &text,
+                lexicons.iter().chain(exclude),
+            ))
+        } else if options.fast_index {
+            eprintln!("Building suffix array in parallel...");
+            build_fast_index(&text)?
+        } else {
+            eprintln!("Building suffix array (this may take a while)...");
+            let suffixtable = build_suffixarray(&text);
+            if let Some(filename) = options.dump_suffix_array.as_deref() {
+                dump_suffix_array(filename, &text, suffixtable.table())
+                    .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+            }
+            Index::SuffixArray(suffixtable)
+        };
+        report_phase(
+            options,
+            "building index",
+            Some(phaseresource),
+            &mut phasestart,
+        );
+
+        let token_count = if options.relative {
+            Some(count_tokens(&text, options))
+        } else {
+            None
+        };
+
+        let excluded = if let Some(exclude) = exclude {
+            find_excluded(&text, &index, exclude, options)
+        } else {
+            Vec::new()
+        };
+        let skipped = if let Some(skip_inside) = options.skip_inside.as_ref() {
+            skip_inside.regions(&text)
+        } else {
+            Vec::new()
+        };
+
+        eprintln!("Searching...");
+        for (j, (lexicon, lexiconname)) in lexicons.iter().zip(lexiconnames.iter()).enumerate() {
+            let freq_threshold = options.freq_threshold(j);
+            if options.group_by_lexicon && !verbose {
+                //verbose output is grouped when the deferred matches are output
+                writeln!(out, "#group\t{}", lexiconname)?;
+            }
+            for (k, item) in matched_lexicon.iter_mut().enumerate() {
+                //matches are attributed to the lexicon currently being searched
+                *item = k == j;
+            }
+            for entry in lexicon.iter() {
+                if (min_token_length >= 1 && entry.chars().count() < min_token_length)
+                    || (options.no_numbers && is_number(entry))
+                {
+                    continue;
+                }
+                let matches = index.positions(entry);
+                let length = entry.as_bytes().len() as u32;
+
+                let matches: Cow<[u32]> = if options.all
+                    && !options.no_cross_newline
+                    && excluded.is_empty()
+                    && skipped.is_empty()
+                {
+                    Cow::Borrowed(matches)
+                } else {
+                    Cow::Owned(
+                        matches
+                            .iter()
+                            .filter(|begin| {
+                                let begin = **begin as usize;
+                                let end = begin + length as usize;
+                                accept_match(&text, begin, end, options)
+                                    && !overlaps(&excluded, begin, end)
+                                    && !overlaps(&skipped, begin, begin + 1)
+                            })
+                            .copied()
+                            .collect(),
+                    )
+                };
+
+                counts.matchcount[j] += matches.len();
+                if track_entries && !matches.is_empty() {
+                    counts.matched_entries[j].insert(entry.clone());
+                }
+                if options.entry_matrix && !matches.is_empty() {
+                    //the same entry in multiple lexicons has the same matches, so don't add them up
+                    counts.entrycount.insert(entry.clone(), matches.len());
+                }
+                if track_forms {
+                    for begin in matches.iter() {
+                        let form = &text[*begin as usize..*begin as usize + length as usize];
+                        if !counts.matched_forms[j].contains(form) {
+                            counts.matched_forms[j].insert(form.to_string());
+                        }
+                    }
+                }
+                let matches: Cow<[u32]> = if options.first_match_only {
+                    //positions are not sorted, so find the earliest one
+                    Cow::Owned(matches.iter().min().into_iter().copied().collect())
+                } else {
+                    matches
+                };
+                if matches.len() >= freq_threshold && matches.len() <= max_freq {
+                    if verbose || buffer_matches {
+                        for begin in matches.iter() {
+                            let begin = *begin as usize;
+                            let end = begin + length as usize;
+                            if buffer_matches {
+                                matchbuffer.push(VerboseMatch {
+                                    text: entry.clone(),
+                                    begin,
+                                    end,
+                                    matched_lexicons: matched_lexicon.clone(),
+                                });
+                            }
+                            if verbose && !deferred {
+                                print_verbose_match(
+                                    out,
+                                    &VerboseRow {
+                                        text: entry,
+                                        begin: begin as u64,
+                                        end: end as u64,
+                                        matched_lexicons: &matched_lexicon,
+                                        lexiconnames: lexiconcolumn,
+                                        resource,
+                                        linecol: line_starts
+                                            .as_deref()
+                                            .map(|l| line_col(&text, l, begin)),
+                                        line_start: options
+                                            .mark_line_start
+                                            .then(|| is_line_start(&text, begin)),
+                                        count: Some(matches.len()),
+                                        ..Default::default()
+                                    },
+                                    options,
+                                )?;
+                                options.count_output()?;
+                            }
+                        }
+                    }
+                    //with --concat, there is a row for each text the entry occurs in
+                    let groups: Vec<(usize, Cow<[u32]>)> =
+                        if let Some(text_begins) = text_begins.as_deref() {
+                            split_matches(&matches, text_begins)
+                        } else {
+                            vec![(textindex, Cow::Borrowed(&matches))]
+                        };
+                    let groups = if let Some(offsetmap) = offsetmap.as_ref() {
+                        //map the offsets back to the text before collapsing whitespace
+                        groups
+                            .into_iter()
+                            .map(|(textindex, matches)| {
+                                let matches: Vec<u32> = matches
+                                    .iter()
+                                    .map(|begin| offsetmap.original(*begin as usize) as u32)
+                                    .collect();
+                                (textindex, Cow::Owned(matches))
+                            })
+                            .collect()
+                    } else {
+                        groups
+                    };
+                    for (textindex, matches) in groups.iter() {
+                        let textfile = options.textfiles[*textindex].as_str();
+                        if options.json_grouped {
+                            print_json_grouped(
+                                out,
+                                entry,
+                                matches,
+                                lexiconname,
+                                textfile,
+                                token_count,
+                                options,
+                            )?;
+                            options.count_output()?;
+                        } else if !verbose && !options.density && !options.gaps {
+                            print_multi_match(
+                                out,
+                                entry,
+                                matches,
+                                lexiconcolumn.map(|lexiconcolumn| lexiconcolumn[j].as_str()),
+                                resource.map(|_| textfile),
+                                token_count,
+                                options,
+                            )?;
+                            options.count_output()?;
+                        }
+                    }
+                } else if options.show_below
+                    && !matches.is_empty()
+                    && matches.len() < freq_threshold
+                {
+                    report_below(entry, matches.len());
+                }
+            }
+        }
+    }
+    report_phase(options, "searching", Some(phaseresource), &mut phasestart);
+    if freq_postfilter {
+        let mut freqs: HashMap<String, usize> = HashMap::new();
+        for m in matchbuffer.iter() {
+            *freqs.entry(m.text.clone()).or_insert(0) += 1;
+        }
+        if options.show_below {
+            let mut below: Vec<(&String, &usize)> = freqs
+                .iter()
+                .filter(|(_, freq)| **freq < freq_threshold)
+                .collect();
+            below.sort_unstable();
+            for (entry, freq) in below {
+                report_below(entry, *freq);
+            }
+        }
+        matchbuffer.retain(|m| {
+            let freq = freqs.get(&m.text).copied().unwrap_or(0);
+            freq >= freq_threshold && freq <= max_freq
+        });
+    }
+    if options.longest_only {
+        retain_longest(&mut matchbuffer, options.no_contained);
+    }
+    matchbuffer.sort_unstable_by_key(|m| m.sortkey());
+    //the entries that contributed to each merged span (--merge-adjacent)
+    let mut merged_entries: Vec<String> = Vec::new();
+    if options.merge_adjacent {
+        (matchbuffer, merged_entries) = merge_adjacent(&text, matchbuffer);
+    }
+    //number of occurrences of each match, in each lexicon ({count} in --format)
+    let mut occurrences: HashMap<(&str, &[bool]), usize> = HashMap::new();
+    if options.format.as_ref().is_some_and(Format::has_count) {
+        for m in matchbuffer.iter() {
+            *occurrences
+                .entry((m.text.as_str(), m.matched_lexicons.as_slice()))
+                .or_insert(0) += 1;
+        }
+    }
+    if deferred && verbose {
+        let contained = if options.mark_contained {
+            contained_matches(&matchbuffer)
+        } else {
+            Vec::new()
+        };
+        let print_deferred = |out: &mut dyn Write,
+                              i: usize,
+                              matched_lexicons: &[bool]|
+         -> Result<(), LexmatchError> {
+            let m = &matchbuffer[i];
+            //with --text-column, offsets are relative to the column in the row and the other columns are output as well
+            let row = rows
+                .as_ref()
+                .map(|rows| &rows[rows.partition_point(|row| row.begin <= m.begin) - 1]);
+            let rowbegin = row.map(|row| row.begin).unwrap_or(0);
+            //with --concat, offsets are relative to the text the match occurs in
+            let (textindex, textbegin) = text_begins
+                .as_deref()
+                .map(|text_begins| {
+                    let i = text_begins.partition_point(|begin| *begin <= m.begin) - 1;
+                    (i, text_begins[i])
+                })
+                .unwrap_or((textindex, 0));
+            //with --collapse-whitespace or --fold-diacritics, offsets refer to the original text
+            let (begin, end) = if let Some(offsetmap) = offsetmap.as_ref() {
+                (offsetmap.original(m.begin), offsetmap.original(m.end))
+            } else {
+                (m.begin, m.end)
+            };
+            let matchtext = unfolded
+                .as_deref()
+                .map_or(m.text.as_str(), |unfolded| &unfolded[begin..end]);
+            print_verbose_match(
+                out,
+                &VerboseRow {
+                    text: matchtext,
+                    begin: (begin - rowbegin - textbegin) as u64,
+                    end: (end - rowbegin - textbegin) as u64,
+                    matched_lexicons,
+                    lexiconnames: lexiconcolumn,
+                    resource: resource.map(|_| options.textfiles[textindex].as_str()),
+                    linecol: line_starts.as_deref().map(|l| line_col(&text, l, m.begin)),
+                    line_start: options
+                        .mark_line_start
+                        .then(|| is_line_start(&text, m.begin)),
+                    contained: contained.get(i).copied(),
+                    extra_columns: row
+                        .map(|row| row.columns.as_str())
+                        .or(merged_entries.get(i).map(|entries| entries.as_str())),
+                    count: occurrences
+                        .get(&(m.text.as_str(), m.matched_lexicons.as_slice()))
+                        .copied(),
+                },
+                options,
+            )?;
+            options.count_output()
+        };
+        if options.group_by_lexicon {
+            //a match in multiple lexicons is output in the group of each of them
+            let mut matched_lexicon = vec![false; lexicons.len()];
+            for (j, lexiconname) in lexiconnames.iter().enumerate() {
+                writeln!(out, "#group\t{}", lexiconname)?;
+                matched_lexicon.fill(false);
+                matched_lexicon[j] = true;
+                for (i, m) in matchbuffer.iter().enumerate() {
+                    if m.matched_lexicons[j] {
+                        print_deferred(out, i, &matched_lexicon)?;
+                    }
+                }
+            }
+        } else {
+            for (i, m) in matchbuffer.iter().enumerate() {
+                print_deferred(out, i, &m.matched_lexicons)?;
             }
         }
     }
-    if texts_len > 1 {
-        print!("\t{}", textfile);
+    if options.density {
+        //leave out the newline that was appended when reading, so it doesn't add an empty line
+        let text = if options.no_append_newline {
+            &text
+        } else {
+            &text[..text.len() - 1]
+        };
+        print_density(out, text, &matchbuffer, resource)?;
+    }
+    if options.gaps {
+        print_gaps(out, &text, &matchbuffer, resource, options)?;
+    }
+    if let Some(folia) = folia {
+        let matches: Vec<Match> = matchbuffer
+            .iter()
+            .flat_map(|m| m.to_matches(lexiconnames))
+            .collect();
+        write_folia_div(folia, textindex, &text, &matches)?;
     }
-    println!("\t{}\t{}", begin, end);
+    Ok(())
 }
 
-#[inline]
-fn print_multi_match(
-    match_text: &str,
-    indices: &[u32],
-    lexiconname: &str,
-    lexicon_len: usize,
-    texts_len: usize,
-    textfile: &str,
-    ignore_matches: bool,
-) {
-    print!("{}", match_text);
-    print!("\t{}", indices.len());
-    if lexicon_len > 1 {
-        print!("\t{}", lexiconname);
+///Report an entry that occurs less often than the frequency threshold on standard error (--show-below)
+fn report_below(entry: &str, freq: usize) {
+    eprintln!("#below\t{}\t{}", entry, freq);
+}
+
+///Report the time spent in a phase to standard error (--benchmark), and start timing the next phase
+fn report_phase(options: &Options, phase: &str, resource: Option<&str>, start: &mut Instant) {
+    if options.benchmark {
+        if let Some(resource) = resource {
+            eprintln!(
+                "Benchmark: {} ({}): {:.3}s",
+                phase,
+                resource,
+                start.elapsed().as_secs_f64()
+            );
+        } else {
+            eprintln!(
+                "Benchmark: {}: {:.3}s",
+                phase,
+                start.elapsed().as_secs_f64()
+            );
+        }
+    }
+    *start = Instant::now();
+}
+
+///Number of lines after which progress is reported (--coverage-matrix)
+const PROGRESS_INTERVAL: usize = 100000;
+
+///Report progress on standard error (--coverage-matrix), with an estimate of the remaining time if the total size of the text (in bytes) is known
+fn report_progress(lines: usize, done: u64, total: Option<u64>, start: Instant) {
+    match total {
+        Some(total) if done > 0 && total >= done => {
+            let remaining = start.elapsed().as_secs_f64() * (total - done) as f64 / done as f64;
+            eprintln!(
+                "Processed {} lines ({:.1}%, about {:.0}s remaining)...",
+                lines,
+                100.0 * done as f64 / total as f64,
+                remaining
+            );
+        }
+        _ => eprintln!("Processed {} lines...", lines),
     }
-    if texts_len > 1 {
-        print!("\t{}", textfile);
+}
+
+///Split the begin offsets of matches in concatenated texts (--concat) by the text they occur in. Returns the index of each text that has matches along with the offsets relative to the start of that text.
+fn split_matches(matches: &[u32], text_begins: &[usize]) -> Vec<(usize, Cow<'static, [u32]>)> {
+    let mut groups: Vec<Vec<u32>> = vec![Vec::new(); text_begins.len()];
+    for begin in matches.iter() {
+        let i = text_begins.partition_point(|textbegin| *textbegin <= *begin as usize) - 1;
+        groups[i].push(*begin - text_begins[i] as u32);
     }
-    if !ignore_matches {
-        //dynamic columns
-        for begin in indices.iter() {
-            print!("\t{}", begin);
+    groups
+        .into_iter()
+        .enumerate()
+        .filter(|(_, group)| !group.is_empty())
+        .map(|(i, group)| (i, Cow::Owned(group)))
+        .collect()
+}
+
+///Escape a string for use in XML
+fn xmlescape(s: &str) -> Cow<'_, str> {
+    if s.contains(['&', '<', '>', '"']) {
+        Cow::Owned(
+            s.replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;"),
+        )
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+///Write the start of a FoLiA XML document, each text will be added as a division (see write_folia_div)
+fn write_folia_header(out: &mut dyn Write) -> Result<(), std::io::Error> {
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        out,
+        "<FoLiA xmlns=\"http://ilk.uvt.nl/folia\" xml:id=\"lexmatch\" version=\"2.5.0\" generator=\"lexmatch\">"
+    )?;
+    writeln!(out, "  <metadata type=\"native\">")?;
+    writeln!(out, "    <annotations>")?;
+    writeln!(out, "      <text-annotation set=\"https://raw.githubusercontent.com/proycon/folia/master/setdefinitions/text.foliaset.ttl\"/>")?;
+    writeln!(out, "      <division-annotation/>")?;
+    writeln!(out, "      <entity-annotation set=\"lexmatch\"/>")?;
+    writeln!(out, "    </annotations>")?;
+    writeln!(out, "  </metadata>")?;
+    writeln!(out, "  <text xml:id=\"lexmatch.text\">")
+}
+
+///Write the end of a FoLiA XML document
+fn write_folia_footer(out: &mut dyn Write) -> Result<(), std::io::Error> {
+    writeln!(out, "  </text>")?;
+    writeln!(out, "</FoLiA>")
+}
+
+///Write a text as a division in FoLiA XML, each match becomes an entity (with the lexicon as class) that refers to the text by offset. Matches must be sorted.
+fn write_folia_div(
+    out: &mut dyn Write,
+    textindex: usize,
+    text: &str,
+    matches: &[Match],
+) -> Result<(), std::io::Error> {
+    let divid = format!("lexmatch.div.{}", textindex + 1);
+    writeln!(out, "    <div xml:id=\"{}\">", divid)?;
+    writeln!(out, "      <t>{}</t>", xmlescape(text.trim_end()))?;
+    if !matches.is_empty() {
+        writeln!(out, "      <entities>")?;
+        //FoLiA offsets are in unicode codepoints rather than bytes, as matches are sorted we compute them incrementally
+        let mut bytepos = 0;
+        let mut charpos = 0;
+        for (i, m) in matches.iter().enumerate() {
+            charpos += text[bytepos..m.begin].chars().count();
+            bytepos = m.begin;
+            writeln!(
+                out,
+                "        <entity xml:id=\"{}.entity.{}\" class=\"{}\">",
+                divid,
+                i + 1,
+                xmlescape(&m.lexicon)
+            )?;
+            //take the text from the offsets, FoLiA requires it to be exactly the referenced substring
+            writeln!(
+                out,
+                "          <t offset=\"{}\">{}</t>",
+                charpos,
+                xmlescape(&text[m.begin..m.end])
+            )?;
+            writeln!(out, "        </entity>")?;
+        }
+        writeln!(out, "      </entities>")?;
+    }
+    writeln!(out, "    </div>")
+}
+
+///Output coverage statistics
+fn print_coverage(
+    out: &mut dyn Write,
+    options: &Options,
+    lexicons: &[Lexicon],
+    lexiconnames: &[String],
+    weights: &[Weights],
+    counts: &Counts,
+) -> Result<(), std::io::Error> {
+    let unit = match options.coverage_denominator {
+        CoverageDenominator::AlphaTokens if options.tokens => "tokens",
+        CoverageDenominator::AlphaTokens if options.whole_line => "lines",
+        CoverageDenominator::AlphaTokens => "characters",
+        CoverageDenominator::AllTokens => "all tokens",
+        CoverageDenominator::Chars => "characters",
+    };
+    let total = counts.coverage_total(options.coverage_denominator);
+    let mut sumcount = 0;
+    for (i, lexiconname) in lexiconnames.iter().enumerate() {
+        let count = counts.coverage_count(i, options.coverage_denominator);
+        sumcount += count;
+        writeln!(
+            out,
+            "#coverage ({} in {}) = {}/{} = {}",
+            unit,
+            lexiconname,
+            count,
+            total,
+            if total == 0 {
+                0.0
+            } else {
+                count as f64 / total as f64
+            }
+        )?;
+    }
+    if lexiconnames.len() > 1 {
+        writeln!(
+            out,
+            "#coverage ({} against all) = {}/{} = {}",
+            unit,
+            sumcount,
+            total,
+            if total == 0 {
+                0.0
+            } else {
+                sumcount as f64 / total as f64
+            }
+        )?;
+    }
+    if options.weighted_coverage {
+        //each match counts with the weight of the entry (or 1) rather than as 1 (or as its number of characters), the total stays the same
+        let mut sumweight = 0.0;
+        for (i, lexiconname) in lexiconnames.iter().enumerate() {
+            let weight: f64 = counts
+                .entrycount
+                .iter()
+                .filter(|(entry, _)| lexicons[i].contains(entry.as_str()))
+                .map(|(entry, count)| {
+                    let count = if options.coverage_denominator == CoverageDenominator::Chars {
+                        *count * count_chars(entry)
+                    } else {
+                        *count
+                    };
+                    count as f64 * weights[i].get(entry).copied().unwrap_or(1.0)
+                })
+                .sum();
+            sumweight += weight;
+            writeln!(
+                out,
+                "#weightedcoverage ({} in {}) = {}/{} = {}",
+                unit,
+                lexiconname,
+                weight,
+                total,
+                if total == 0 {
+                    0.0
+                } else {
+                    weight / total as f64
+                }
+            )?;
+        }
+        if lexiconnames.len() > 1 {
+            writeln!(
+                out,
+                "#weightedcoverage ({} against all) = {}/{} = {}",
+                unit,
+                sumweight,
+                total,
+                if total == 0 {
+                    0.0
+                } else {
+                    sumweight / total as f64
+                }
+            )?;
+        }
+    }
+    Ok(())
+}
+
+///Print a table with the number of matches for each text (rows) and lexicon (columns), as TSV (--summary-matrix)
+fn print_summary_matrix(
+    out: &mut dyn Write,
+    texts: &[String],
+    lexiconnames: &[String],
+    textmatchcounts: &[Vec<usize>],
+) -> Result<(), std::io::Error> {
+    write!(out, "Resource")?;
+    for lexiconname in lexiconnames.iter() {
+        write!(out, "\t{}", lexiconname)?;
+    }
+    if lexiconnames.len() > 1 {
+        write!(out, "\tTotal")?;
+    }
+    writeln!(out)?;
+    for (textfile, matchcount) in texts.iter().zip(textmatchcounts.iter()) {
+        write!(out, "{}", textfile)?;
+        for count in matchcount.iter() {
+            write!(out, "\t{}", count)?;
+        }
+        if lexiconnames.len() > 1 {
+            write!(out, "\t{}", matchcount.iter().sum::<usize>())?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+///Print a table with the number of matches for each lexicon entry (rows) and text (columns), as TSV (--entry-matrix). Only entries that matched at least once are included.
+fn print_entry_matrix(
+    out: &mut dyn Write,
+    texts: &[String],
+    textentrycounts: &[HashMap<String, usize>],
+) -> Result<(), std::io::Error> {
+    write!(out, "Entry")?;
+    for textfile in texts.iter() {
+        write!(out, "\t{}", textfile)?;
+    }
+    writeln!(out)?;
+    let mut entries: Vec<&String> = textentrycounts
+        .iter()
+        .flat_map(|entrycount| entrycount.keys())
+        .collect::<HashSet<&String>>()
+        .into_iter()
+        .collect();
+    entries.sort_unstable();
+    for entry in entries {
+        write!(out, "{}", entry)?;
+        for entrycount in textentrycounts.iter() {
+            write!(out, "\t{}", entrycount.get(entry).copied().unwrap_or(0))?;
         }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+///The names of the columns of the verbose output, as in the header
+fn verbose_columns(
+    options: &Options,
+    lexicon_count: usize,
+    text_count: usize,
+) -> Vec<&'static str> {
+    let offsets = [
+        "BeginUtf8Offset",
+        if options.inclusive_end {
+            "InclusiveEndUtf8Offset"
+        } else {
+            "EndUtf8Offset"
+        },
+    ];
+    let mut columns = Vec::new();
+    if options.positions_only {
+        columns.extend(offsets);
+    } else {
+        columns.push("Text");
+    }
+    if lexicon_count > 1 || options.stable_columns {
+        columns.push("Lexicon");
+    }
+    if text_count > 1 || options.stable_columns {
+        columns.push("Resource");
+    }
+    if !options.positions_only {
+        columns.extend(offsets);
+    }
+    if options.line_col {
+        columns.extend(["Line", "Column"]);
+    }
+    if options.mark_line_start {
+        columns.push("LineStart");
+    }
+    if options.mark_contained {
+        columns.push("Contained");
     }
-    println!();
+    if options.with_length {
+        columns.push("Length");
+    }
+    if options.merge_adjacent {
+        columns.push("Entries");
+    }
+    columns
+}
+
+///Output the header with the names of the columns, as TSV for verbose output or as a JSON array (--header-json)
+fn print_header(
+    out: &mut dyn Write,
+    columns: &[&str],
+    options: &Options,
+) -> Result<(), std::io::Error> {
+    if options.header_json {
+        //the column names need no escaping
+        let columns: Vec<String> = columns
+            .iter()
+            .map(|column| format!("\"{}\"", column))
+            .collect();
+        writeln!(out, "[{}]", columns.join(","))?;
+    } else if (options.verbose_output() || options.gaps) && options.format.is_none() {
+        writeln!(out, "{}", columns.join("\t"))?;
+    }
+    Ok(())
+}
+
+///Output statistics summarizing the run (to stderr)
+fn print_stats(lexicons: &[Lexicon], lexiconnames: &[String], counts: &Counts, elapsed: Duration) {
+    let entries: HashSet<&String> = lexicons.iter().flat_map(|lexicon| lexicon.iter()).collect();
+    let matched_entries: HashSet<&String> = counts
+        .matched_entries
+        .iter()
+        .flat_map(|entries| entries.iter())
+        .collect();
+    eprintln!("Statistics:");
+    eprintln!(
+        "  Total matches: {}",
+        counts.matchcount.iter().sum::<usize>()
+    );
+    eprintln!("  Distinct entries matched: {}", matched_entries.len());
+    eprintln!(
+        "  Entries without matches: {}",
+        entries.difference(&matched_entries).count()
+    );
+    for (lexiconname, matchcount) in lexiconnames.iter().zip(counts.matchcount.iter()) {
+        eprintln!("  Matches in {}: {}", lexiconname, matchcount);
+    }
+    eprintln!("  Elapsed time: {:.3}s", elapsed.as_secs_f64());
 }
 
 fn main() {
-    let args = App::new("Lexmatch")
+    if let Err(err) = run() {
+        if !matches!(err, LexmatchError::NoMatches) {
+            eprintln!("ERROR: {}", err);
+        }
+        exit(err.exitcode());
+    }
+}
+
+///The command-line interface
+fn app() -> App<'static> {
+    App::new("Lexmatch")
                     .version("0.3")
                     .author("Maarten van Gompel (proycon) <proycon@anaproy.nl>")
                     .about("Simple lexicon matcher powered by either suffix arrays or hash tables.")
@@ -118,7 +4353,17 @@ fn main() {
                     .arg(Arg::with_name("lexicon")
                         .long("lexicon")
                         .short('l')
-                        .help("The lexicon to use, has one entry on each line. If the input is TSV, only the first columns is considered. Entries may also be phrases/n-grams unless --tokens is set. Multiple lexicons are supported (and will be reflected in the output)")
+                        .help("The lexicon to use, has one entry on each line. If the input is TSV, only the first columns is considered. A lexicon whose filename ends in .gz is decompressed. Entries may also be phrases/n-grams unless --tokens is set. Multiple lexicons are supported (and will be reflected in the output)")
+                        .multiple_occurrences(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name("lexicon-name")
+                        .long("lexicon-name")
+                        .help("The name to use for a lexicon in the output rather than its filename. May be given multiple times, the names are paired with the lexicons in the order they are given (first those from --lexicon, then those from --lexicon-dir). Lexicons without a name are referred to by their filename, as given for --lexicon or without the directory for --lexicon-dir.")
+                        .multiple_occurrences(true)
+                        .takes_value(true))
+                    .arg(Arg::with_name("lexicon-dir")
+                        .long("lexicon-dir")
+                        .help("A directory with lexicons, every .tsv or .txt file in it (optionally gzipped, i.e. .tsv.gz or .txt.gz) is loaded as a separate lexicon, in alphabetical order, as if passed with --lexicon, and is named by its file name (without the directory). Other files are skipped with a warning. May be combined with --lexicon and given multiple times.")
                         .multiple_occurrences(true)
                         .takes_value(true))
                     .arg(Arg::with_name("query")
@@ -128,33 +4373,72 @@ fn main() {
                         .takes_value(true)
                         .number_of_values(1)
                         .multiple(true))
+                    .arg(Arg::with_name("query-file")
+                        .long("query-file")
+                        .help("A file with words/phrases to lookup, one per line; the entire line is taken as the query (no TSV parsing as with --lexicon). Can be combined with --query.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("stoplist")
+                        .long("stoplist")
+                        .help("A list of words/phrases (same format as a lexicon) that will never be matched, they are removed from all lexicons")
+                        .takes_value(true))
                     .arg(Arg::with_name("all")
                         .long("all")
                         .short('a')
                         .help("Return all matches (also as substrings), rather than only exact matches. This is already implied when using --tokens or --cjk.")
                         .required(false))
+                    .arg(Arg::with_name("longest-only")
+                        .long("longest-only")
+                        .help("With --all; of the matches that start at the same position, only return the longest. Requires --verbose, matches are only output once the entire text has been processed.")
+                        .requires("all")
+                        .required(false))
+                    .arg(Arg::with_name("no-contained")
+                        .long("no-contained")
+                        .help("With --longest-only; also drop matches that are fully contained in a longer match that starts earlier, so no match is nested in another.")
+                        .requires("longest-only")
+                        .required(false))
+                    .arg(Arg::with_name("no-cross-newline")
+                        .long("no-cross-newline")
+                        .help("Reject matches that span across a newline, keeping phrase matches within a single line. Does not work with --tokens/--cjk.")
+                        .required(false))
                     .arg(Arg::with_name("verbose")
                         .long("verbose")
                         .short('v')
                         .help("Return output verbosely as TSV with each match on a separate row. Will output a header on the first line. Implied when --tokens or --cjk is set.")
                         .required(false))
+                    .arg(Arg::with_name("sort")
+                        .long("sort")
+                        .help("Sort verbose output by begin offset, end offset and lexicon (rather than in arbitrary order). This buffers all matches for a text in memory before outputting them.")
+                        .required(false))
+                    .arg(Arg::with_name("line-col")
+                        .long("line-col")
+                        .help("Also output the line number and column (character position in the line) of the begin offset of each match, both 1-based. Only applies to verbose output.")
+                        .required(false))
                     .arg(Arg::with_name("tokens")
                         .long("tokens")
                         .alias("hash")
                         .short('T')
                         .help("Do a simple token-based lookup using a hash-table instead of using suffix arrays. This is usually faster but more limited (no thresholds etc). Only works on languages with whitespace/punctuation, use --cjk instead for Chinese/Japanese/Korean text.")
                         .required(false))
+                    .arg(Arg::with_name("aho-corasick")
+                        .long("aho-corasick")
+                        .help("Find matches using an Aho-Corasick automaton built from the lexicon rather than using a suffix array built from the text. Produces the same output as the default, but is usually faster for large lexicons as the text is scanned only once.")
+                        .required(false))
                     .arg(Arg::with_name("coverage")
                         .long("coverage")
                         .help("With --tokens; compute how many tokens are covered by the lexicon. With --cjk; on a character basis.")
                         .required(false))
+                    .arg(Arg::with_name("oov")
+                        .long("oov")
+                        .help("With --tokens; output all tokens that are not in any lexicon (out-of-vocabulary), along with their frequency, sorted by descending frequency. With --cjk; on a character basis. These are output at the end, on lines starting with #oov")
+                        .required(false))
                     .arg(Arg::with_name("coverage-matrix")
                         .long("coverage-matrix")
                         .help("For each line in the input, compute the coverage in the lexicons")
                         .required(false))
                     .arg(Arg::with_name("min-token-length")
                         .long("min-token-length")
-                        .help("Minimum token length to consider, shorter tokens will be ignored and not matched")
+                        .alias("min-match-length")
+                        .help("Minimum token length in characters to consider, shorter tokens will be ignored and not matched. Without --tokens, lexicon entries shorter than this are skipped before searching, which avoids huge numbers of mostly useless matches for very short entries (especially with --all). Also available as --min-match-length.")
                         .takes_value(true)
                         .default_value("1")
                         .required(false))
@@ -165,389 +4449,1086 @@ fn main() {
                         .help("Do a greedy character-based lookup using a hash-table instead of using suffix arrays. The value corresponds to the maximum number of characters to consider. Use this instead of --tokens for languages like Chinese, Japanese, Korean, use --tokens if the language uses whitesapce and punctuation as token delimiter.")
                         .takes_value(true)
                         .required(false))
+                    .arg(Arg::with_name("cjk-min")
+                        .long("cjk-min")
+                        .help("With --cjk; the minimum number of characters to consider, shorter patterns are never matched. Defaults to 1.")
+                        .takes_value(true)
+                        .requires("cjk")
+                        .required(false))
+                    .arg(Arg::with_name("cjk-tile")
+                        .long("cjk-tile")
+                        .help("With --cjk; after finding the longest match at a position, continue after the matched span rather than at the next character. This produces a non-overlapping segmentation.")
+                        .requires("cjk")
+                        .required(false))
                     .arg(Arg::with_name("no-case")
                         .long("no-case")
                         .alias("case-insensitive")
                         .short('i')
-                        .help("Case insensitive matching. (Warning: This *MAY* result in rare cases result in offsets that no longer match the original text!)")
+                        .help("Case insensitive matching. (Warning: This *MAY* result in rare cases result in offsets that no longer match the original text! Use --ascii-no-case instead if only ASCII letters need to be matched case insensitively, that never affects the offsets)")
                         .required(false))
                     .arg(Arg::with_name("no-matches")
                         .long("count-only")
                         .alias("no-matches")
                         .short('M')
-                        .help("Don't return matching indices, only return the number of matches. Does not work with --tokens or --cjk")
+                        .help("Don't return matching indices, only return the number of matches. With --tokens or --cjk, only the total number of matches for each lexicon is output, once all texts have been processed.")
                         .required(false))
                     .arg(Arg::with_name("freq")
                         .long("freq")
                         .short('f')
-                        .help("An absolute frequency threshold, return only matches above this threshold, defaults to 1, set to 0 to return the entire lexicon. Does not work with --tokens/--cjk.")
+                        .help("An absolute frequency threshold, return only matches above this threshold, defaults to 1, set to 0 to return the entire lexicon. May be given multiple times for a threshold for each lexicon, in the same order as --lexicon; lexicons without a threshold of their own (including --query) use the last one given. With --tokens/--cjk, matches are only output once the entire text has been processed, and only for entries that occur at least this often in the text.")
+                        .takes_value(true)
+                        .multiple_occurrences(true)
+                        .default_value("1"))
+                    .arg(Arg::with_name("show-below")
+                        .long("show-below")
+                        .help("Report the entries that occur in the text but less often than the frequency threshold (--freq) on standard error, as a line '#below', the entry and its count, rather than dropping them silently. The output itself still respects the threshold.")
+                        .required(false))
+                    .arg(Arg::with_name("max-freq")
+                        .long("max-freq")
+                        .help("An absolute maximum frequency, return only matches for entries that occur at most this often. Combined with --freq, this returns only entries with a frequency within that range. With --tokens/--cjk, matches are only output once the entire text has been processed.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("relative")
+                        .long("relative")
+                        .help("Add a column with the relative frequency after the absolute count. The denominator is the total number of tokens in the text, where tokens are delimited as with --tokens (regardless of whether they match). Can not be used with --verbose.")
+                        .required(false))
+                    .arg(Arg::with_name("normalize-newlines")
+                        .long("normalize-newlines")
+                        .help("Convert Windows-style newlines (CRLF) in the text to Unix-style (LF) before matching. Note that offsets then refer to the normalized text rather than the original file.")
+                        .required(false))
+                    .arg(Arg::with_name("extract")
+                        .long("extract")
+                        .help("Instead of matching, read a TSV file with begin and end offsets (UTF-8 bytes) in the first two columns, and output the text at these offsets. No lexicon is needed in this mode.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("stream")
+                        .long("stream")
+                        .help("With --tokens, --cjk, --whole-line or --coverage-matrix; read the text (or standard input) line by line rather than loading it into memory entirely. This uses 64-bit offsets so there is no limit on the size of the text. Can not be combined with --sort, --folia or a frequency threshold. With --cjk, matches never span multiple lines.")
+                        .conflicts_with_all(&["sort", "folia"])
+                        .required(false))
+                    .arg(Arg::with_name("no-numbers")
+                        .long("no-numbers")
+                        .help("Never match numbers (anything with digits but without alphabetic characters), so they do not count as matches nor towards coverage. Numeric tokens are always ignored by --tokens and --coverage-matrix already, this extends that behaviour to lexicon entries in the default mode, to patterns with --cjk, to lines with --whole-line and to the lexicon entries found with --prefix.")
+                        .required(false))
+                    .arg(Arg::with_name("stable-columns")
+                        .long("stable-columns")
+                        .help("Always output the lexicon and resource (text file) columns, even if there is only one lexicon or text, so the column layout does not depend on the input. Queries (--query, --query-file) form a lexicon of their own named 'query'.")
+                        .required(false))
+                    .arg(Arg::with_name("boundary")
+                        .long("boundary")
+                        .help("Determines what counts as a boundary for exact matches (i.e. when --all is not set), the characters directly before and after a match must be boundaries. 'alnum': anything not alphanumeric, 'whitespace': only whitespace, 'word': anything not alphabetic (so digits are boundaries too).")
+                        .takes_value(true)
+                        .possible_values(["alnum", "whitespace", "word"])
+                        .default_value("alnum"))
+                    .arg(Arg::with_name("config")
+                        .long("config")
+                        .help("Read default settings from this TOML configuration file. Keys are the long names of the command-line options (e.g. lexicon = [\"a.lst\", \"b.lst\"], no-case = true, freq = 2), options given on the command line take precedence. Text files must always be given on the command line.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("inclusive-end")
+                        .long("inclusive-end")
+                        .help("Output end offsets that are inclusive (pointing at the last byte of the match) rather than exclusive (pointing just after the match, the default). The header column is then called InclusiveEndUtf8Offset. Also applies to the offsets read by --extract.")
+                        .required(false))
+                    .arg(Arg::with_name("text-column")
+                        .long("text-column")
+                        .help("The text is TSV, match only against the text in this column (1-based). Offsets are relative to the column in each row, and the other columns of the row are output after each match (named Column1, Column2, etc. after their position in the header). Requires verbose output (or --coverage-matrix).")
+                        .takes_value(true)
+                        .conflicts_with("stream"))
+                    .arg(Arg::with_name("fast-index")
+                        .long("fast-index")
+                        .help("Build the suffix array in parallel (using all available cores) rather than with the default single-threaded implementation. Only available if lexmatch was built with the fast-index feature.")
+                        .conflicts_with("aho-corasick")
+                        .required(false))
+                    .arg(Arg::with_name("with-length")
+                        .long("with-length")
+                        .help("Add a Length column to the verbose output with the length of each match in characters (unlike the offsets, which are in bytes).")
+                        .required(false))
+                    .arg(Arg::with_name("validate")
+                        .long("validate")
+                        .help("Only load the lexicons and report on each of them (number of entries, duplicates removed, empty lines skipped, shortest and longest entry), then exit without reading any text. Useful to catch malformed lexicons before a long run.")
+                        .required(false))
+                    .arg(Arg::with_name("lexicon-hash")
+                        .long("lexicon-hash")
+                        .help("Only load the lexicons (and queries) and output a stable hash of each of them, computed over the sorted entries after all lexicon options (case, stoplist, whitespace collapsing) are applied, then exit without reading any text. Useful to detect whether cached results are still up to date.")
+                        .conflicts_with_all(&["validate", "bytes", "extract"])
+                        .required(false))
+                    .arg(Arg::with_name("json-grouped")
+                        .long("json-grouped")
+                        .help("Output a JSON object for each matching lexicon entry (one per line) with the entry, lexicon, resource (text file), count and the begin offsets of all matches (positions), rather than TSV. Positions are omitted with --count-only.")
+                        .required(false))
+                    .arg(Arg::with_name("lossy")
+                        .long("lossy")
+                        .help("Do not fail on text that is not valid UTF-8, but replace invalid byte sequences with the Unicode replacement character (U+FFFD) and report how many were replaced. Offsets then refer to the text after replacement.")
+                        .required(false))
+                    .arg(Arg::with_name("encoding")
+                        .long("encoding")
+                        .help("The character encoding of the texts, lexicons and query files, e.g. 'latin1', 'windows-1252' or 'utf-16le' (any label from the WHATWG Encoding Standard). Input is transcoded to UTF-8 before processing, so offsets then refer to the transcoded UTF-8 text rather than the original bytes. A byte order mark overrides the given encoding. Defaults to UTF-8, which is read without any transcoding.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["stream", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("mark-line-start")
+                        .long("mark-line-start")
+                        .help("Add a LineStart column to the verbose output that is true if the match is at the start of a line (at the start of the text or right after a newline) and false otherwise.")
+                        .required(false))
+                    .arg(Arg::with_name("concat")
+                        .long("concat")
+                        .help("Concatenate all texts and build a single suffix array over them, rather than one for each text. This is faster for many small texts. Matches are still reported for the text they occur in, with offsets relative to that text, but frequency thresholds apply to all texts together. The texts may not exceed 4GB in total.")
+                        .conflicts_with_all(&["text-column", "folia", "line-col", "relative", "stream"])
+                        .required(false))
+                    .arg(Arg::with_name("manifest")
+                        .long("manifest")
+                        .help("A file listing the texts (one filename per line) that together form a single document, for instance one file per page. The texts are processed in the order of the manifest as with --concat (which this implies): matches are reported for the text they occur in with offsets relative to that text. Any texts given on the command line come first.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["text-column", "folia", "line-col", "relative", "stream"])
+                        .required(false))
+                    .arg(Arg::with_name("dump-suffix-array")
+                        .long("dump-suffix-array")
+                        .help("For debugging: write the suffix array that is built from the text to this file ('-' for standard error), a line for each suffix in sorted order with its begin offset and the first bytes of the suffix. Beware that this file has a line for every byte of the text, so it is many times larger than the text itself. Requires a single text (or --concat). Only available if lexmatch was built with the debug-dump feature.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["aho-corasick", "fast-index", "tokens", "cjk", "whole-line", "coverage-matrix", "stream", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("benchmark")
+                        .long("benchmark")
+                        .help("Report the time spent in each phase to standard error: reading the lexicons, and for each text reading the text, building the index (suffix array or Aho-Corasick automaton) and searching. Useful to compare --tokens, --aho-corasick and the default suffix arrays.")
+                        .required(false))
+                    .arg(Arg::with_name("summary-matrix")
+                        .long("summary-matrix")
+                        .help("At the end, output a TSV table with the number of matches for each text (rows) and lexicon (columns), with a total column if there are multiple lexicons.")
+                        .conflicts_with_all(&["concat", "coverage-matrix"])
+                        .required(false))
+                    .arg(Arg::with_name("entry-matrix")
+                        .long("entry-matrix")
+                        .help("At the end, output a TSV table with the number of matches for each lexicon entry (rows) and text (columns), i.e. a term-document matrix. Only entries that matched at least once are included.")
+                        .conflicts_with_all(&["concat", "coverage-matrix", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("collapse-whitespace")
+                        .long("collapse-whitespace")
+                        .help("For matching, collapse each run of whitespace (including newlines) to a single space, both in the text and in the lexicons. This allows phrases to match regardless of how the text is wrapped over lines. Offsets in the output still refer to the original text.")
+                        .conflicts_with_all(&["text-column", "folia", "line-col", "mark-line-start", "concat", "no-cross-newline"])
+                        .required(false))
+                    .arg(Arg::with_name("fold-diacritics")
+                        .long("fold-diacritics")
+                        .help("Remove diacritics before matching, so for instance 'cafe' and 'café' match. The value determines on which side: 'lexicon' for lexicons with and text without diacritics, 'text' for lexicons without and text with diacritics, or 'both'. Offsets in the output still refer to the original text, and the matched text that is output is taken from the original (unfolded) text.")
+                        .takes_value(true)
+                        .possible_values(["lexicon", "text", "both"])
+                        .conflicts_with("bytes"))
+                    .arg(Arg::with_name("min-coverage")
+                        .long("min-coverage")
+                        .help("With --coverage, exit with status 4 if the coverage over all lexicons and texts is below this ratio (e.g. 0.8). The coverage is still output as usual, this is meant as a check in automated pipelines.")
+                        .takes_value(true)
+                        .requires("coverage"))
+                    .arg(Arg::with_name("coverage-denominator")
+                        .long("coverage-denominator")
+                        .help("What the coverage (--coverage, --coverage-matrix) is computed against. 'alpha-tokens': tokens with at least one alphabetic character, 'all-tokens': all tokens including numbers, 'chars': all characters except whitespace, the matches then count with their number of characters (except whitespace) rather than as 1.")
+                        .takes_value(true)
+                        .possible_values(["alpha-tokens", "all-tokens", "chars"])
+                        .default_value("alpha-tokens"))
+                    .arg(Arg::with_name("bytes")
+                        .long("bytes")
+                        .help("Match raw bytes rather than UTF-8 text, neither the lexicons nor the text need to be valid UTF-8. Offsets are byte offsets without any regard for character boundaries, and matches that are not valid UTF-8 are output with replacement characters. Exact matches are delimited by any byte that is not ASCII alphanumeric. Matching is done using an Aho-Corasick automaton, --no-case only applies to ASCII. Only --verbose, --count-only, --all, --freq, --max-freq, --max-matches, --stoplist, --inclusive-end and --stable-columns are supported in this mode.")
+                        .conflicts_with_all(&["text-column", "folia", "line-col", "mark-line-start", "concat", "stream", "collapse-whitespace", "relative", "json-grouped", "sort", "lossy", "normalize-newlines"])
+                        .required(false))
+                    .arg(Arg::with_name("no-append-newline")
+                        .long("no-append-newline")
+                        .help("Do not append a newline to the text after reading it. By default a newline is always appended to ensure the text ends with one, pass this if your input is already newline-terminated, or if it is important that offsets and counts near the end of the text are not affected.")
+                        .conflicts_with("concat")
+                        .required(false))
+                    .arg(Arg::with_name("conllu")
+                        .long("conllu")
+                        .help("Output all tokens of the text in CoNLL-U format (requires --tokens) rather than only the matches. Each line of the text is a sentence, each token a row, and the lexicons a token was found in are listed in the MISC column (e.g. Lexicon=en.lst). The other columns are left empty (_).")
+                        .conflicts_with_all(&["verbose", "no-matches", "folia", "text-column", "sort", "freq", "max-freq"])
+                        .required(false))
+                    .arg(Arg::with_name("first-match-only")
+                        .long("first-match-only")
+                        .help("Only report the first occurrence (the lowest begin offset) of each lexicon entry, with a count of 1. Useful if you only need to know whether an entry occurs at all. Statistics (--stats) still consider all occurrences.")
+                        .conflicts_with_all(&["freq", "max-freq", "relative", "concat", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("case-fold")
+                        .long("case-fold")
+                        .help("Case insensitive matching using full Unicode case folding rather than lowercasing (as --no-case does), so for instance 'Straße' matches 'STRASSE'. All input and output will be case folded. (Warning: case folding may change the length of the text, offsets then no longer match the original text!)")
+                        .conflicts_with_all(&["no-case", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("ascii-no-case")
+                        .long("ascii-no-case")
+                        .help("Case insensitive matching for ASCII letters only (A-Z), other characters are matched case sensitively. Unlike --no-case, this never changes the length of the text, so offsets always match the original text, and it is faster. Suitable for English text.")
+                        .conflicts_with_all(&["no-case", "case-fold"])
+                        .required(false))
+                    .arg(Arg::with_name("lexicon-ids")
+                        .long("lexicon-ids")
+                        .help("Refer to lexicons by their index (starting at 0, in the order they were passed) rather than their name in the lexicon column, multiple lexicons are delimited by a semicolon. A legend mapping the indices to the names is output first (#lexicon lines).")
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("alnum-chars")
+                        .long("alnum-chars")
+                        .help("Only consider these characters alphanumeric, rather than all Unicode letters and digits. This affects tokenization (--tokens, --coverage, --coverage-matrix, --relative) and the boundaries of exact matches with --boundary alnum. Characters and ranges are given like a character class in a regular expression, e.g. 'a-zA-Z0-9'.")
+                        .takes_value(true)
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("exact-phrase")
+                        .long("exact-phrase")
+                        .help("Exact matches must align with the tokens of the text: a match may not start or end in the middle of a token (as delimited by --alnum-chars), so 'New York' does not match 'New Yorker'. Unlike the --boundary test, this also allows lexicon entries that start or end with punctuation, e.g. '.com' matches in 'example.com'. Replaces the --boundary test.")
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("offset-base")
+                        .long("offset-base")
+                        .help("Add this number to all offsets in the output. Useful if the text is a part of a larger document (e.g. a shard of a corpus) and you want offsets in that document.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["extract", "folia"])
+                        .required(false))
+                    .arg(Arg::with_name("prefix")
+                        .long("prefix")
+                        .help("Match lexicon entries at the start of words: only the character directly before a match must be a boundary (see --boundary), anything may follow it. For instance, 'walk' then also matches in 'walked'.")
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("suffix")
+                        .long("suffix")
+                        .help("Match lexicon entries at the end of words: only the character directly after a match must be a boundary (see --boundary), anything may precede it. For instance, 'ing' then matches in 'walking'. Use --all to match anywhere.")
+                        .conflicts_with_all(&["prefix", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("quiet")
+                        .long("quiet")
+                        .help("Do not report progress to standard error. With --coverage-matrix, progress is reported every 100000 lines by default.")
+                        .required(false))
+                    .arg(Arg::with_name("format")
+                        .long("format")
+                        .help("Output each match according to this template rather than the default columns (requires --verbose, --tokens or --cjk). Placeholders are {text}, {begin}, {end}, {lexicon}, {file} and {count} (the number of occurrences of the match), \\t and \\n are a tab and a newline. For example: '{begin}\\t{end}\\t{text}'. No header is output.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["line-col", "mark-line-start", "with-length", "text-column", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("exclude-lexicon")
+                        .long("exclude-lexicon")
+                        .help("A lexicon of entries that veto other matches: any match that overlaps with a match of an entry from this lexicon is dropped. For instance, with 'river bank' in this lexicon, 'bank' no longer matches in 'river bank'. The entries of this lexicon are not reported themselves.")
+                        .takes_value(true)
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("token-regex")
+                        .long("token-regex")
+                        .help("With --tokens, the tokens are the matches of this regular expression rather than runs of alphanumeric characters (see --alnum-chars), e.g. '[\\p{L}\\p{M}]+' for letters including combining marks. This gives full control over the tokenization for unusual scripts. As usual, only tokens with at least one alphabetic character are looked up. The text is scanned in a single thread.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["stream", "max-gap", "conllu", "coverage-matrix", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("fst")
+                        .long("fst")
+                        .help("With --tokens, look up the tokens in finite state transducers (FSTs) built from the lexicons rather than in hash tables. This takes considerably less memory for large lexicons with many shared prefixes, at the cost of building the FSTs first. Can be combined with --prefix to match tokens that start with a lexicon entry, the longest such entry is then output as the match.")
+                        .conflicts_with_all(&["stream", "conllu", "coverage-matrix", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("max-gap")
+                        .long("max-gap")
+                        .help("With --tokens, also match lexicon entries that consist of multiple tokens, allowing up to this many other tokens between each token of the entry and the next (0 only matches the tokens of the entry directly after each other). For instance, with --max-gap 2, 'machine learning' also matches in 'machine based deep learning'. The match spans from the first to the last token of the entry, the lexicon entry is output as the matched text. Output is deferred until all matches of a text are found.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["stream", "cjk", "whole-line"])
+                        .required(false))
+                    .arg(Arg::with_name("skip-inside")
+                        .long("skip-inside")
+                        .help("Skip matches that begin inside a region enclosed by delimiters, e.g. in parentheses or quotation marks. The value is a string of pairs of an opening and a closing character, e.g. '()[]\"\"'. Nested delimiters of the same pair are respected, a delimiter that is not closed is ignored.")
+                        .takes_value(true)
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("whole-line")
+                        .long("whole-line")
+                        .help("Look up each line of the text as a whole (without leading and trailing whitespace) using a hash-table, rather than searching for lexicon entries within the text. Only lines that are exactly equal to a lexicon entry match, the output is like that of --tokens with the offsets of the line.")
+                        .conflicts_with_all(&["tokens", "cjk", "coverage-matrix", "conllu", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("weighted-coverage")
+                        .long("weighted-coverage")
+                        .help("With --coverage, also output the coverage where each match counts with the weight of the lexicon entry rather than as 1. The weight is read from the second column of the lexicon (e.g. a frequency), entries without a numeric weight have weight 1. The denominator is the same as for the normal coverage (see --coverage-denominator), not the total weight.")
+                        .requires("coverage")
+                        .conflicts_with_all(&["entry-matrix", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("skip-bad-lexicons")
+                        .long("skip-bad-lexicons")
+                        .help("If a lexicon can not be read, report it on standard error and continue with the other lexicons rather than aborting. The run is only aborted if none of the lexicons can be read.")
+                        .required(false))
+                    .arg(Arg::with_name("density")
+                        .long("density")
+                        .help("Output the match density of each line rather than the matches: a TSV with the line number and the fraction of the characters of the line that is covered by any match. Unlike --coverage-matrix, which counts matching tokens, this is computed from the character spans of the actual matches (so it also works for substring matches). Can be combined with --tokens, --cjk or --whole-line, as well as with options that filter matches (e.g. --freq).")
+                        .conflicts_with_all(&["verbose", "coverage-matrix", "conllu", "json-grouped", "stream", "concat", "collapse-whitespace", "text-column", "group-by-lexicon", "no-matches", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("gaps")
+                        .long("gaps")
+                        .help("Output the gaps rather than the matches: the stretches of the text that are not covered by any match, with their text and offsets like verbose matches. Gaps do not span multiple lines, and leading and trailing whitespace is not part of a gap. Can be combined with --tokens, --cjk or --whole-line, as well as with options that filter matches (e.g. --freq).")
+                        .conflicts_with_all(&["verbose", "density", "coverage-matrix", "conllu", "json-grouped", "stream", "concat", "collapse-whitespace", "text-column", "group-by-lexicon", "no-matches", "line-col", "mark-line-start", "mark-contained", "merge-adjacent", "format", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("merge-adjacent")
+                        .long("merge-adjacent")
+                        .help("Merge matches that overlap or touch (with no characters in between) into a single span in the verbose output, e.g. for visualizing the coverage of the text. The text of the span is output as the matched text, followed by the offsets of the span; a final Entries column lists the distinct matches that the span consists of (delimited by a semicolon). Output is deferred until all matches of a text are found.")
+                        .conflicts_with_all(&["mark-contained", "text-column", "format", "folia", "stream", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("mark-contained")
+                        .long("mark-contained")
+                        .help("Add a Contained column to the verbose output that indicates whether the match is fully contained in a longer match that is also output, for instance 'art' in 'artist' with --all. Output is deferred until all matches of a text are found.")
+                        .conflicts_with_all(&["stream", "format", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("positions-only")
+                        .long("positions-only")
+                        .help("Leave out the text of each match in the verbose output, the begin and end offsets then come first, followed by the lexicon and resource (if output). This reduces the size of the output considerably with --tokens on texts with a high coverage.")
+                        .conflicts_with_all(&["format", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("header-json")
+                        .long("header-json")
+                        .help("Output the header of the verbose output as a JSON array with the names of the columns that follow (on the first line), rather than as TSV. The columns depend on the other options, this makes the output easier to parse.")
+                        .conflicts_with_all(&["format", "text-column", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("limit")
+                        .long("limit")
+                        .help("Stop after this many matches have been output in total, over all lexicons and texts. Without verbose output, each row (one for each lexicon entry) counts as one. Combine with --first-match-only for a quick preview of a large run. Texts are then processed one at a time, even with --threads.")
+                        .takes_value(true)
+                        .conflicts_with_all(&["folia", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("group-by-lexicon")
+                        .long("group-by-lexicon")
+                        .help("Output the matches of each lexicon together, the first lexicon first, with each group preceded by a line '#group' followed by a tab and the name of the lexicon. A match that is in multiple lexicons is output in each group. Grouping is done for each text separately.")
+                        .conflicts_with_all(&["json-grouped", "conllu", "coverage-matrix", "stream", "bytes"])
+                        .required(false))
+                    .arg(Arg::with_name("csv-lexicon")
+                        .long("csv-lexicon")
+                        .help("Read the lexicons (and stoplist) as tab separated values in which fields may be quoted with double quotes, as in CSV, so entries can contain tabs. A double quote inside a quoted field is written as two double quotes. Only the first field is used as the entry.")
+                        .conflicts_with("bytes")
+                        .required(false))
+                    .arg(Arg::with_name("output")
+                        .long("output")
+                        .short('o')
+                        .help("Write the output to this file rather than to standard output ('-' is standard output)")
+                        .takes_value(true))
+                    .arg(Arg::with_name("folia")
+                        .long("folia")
+                        .help("Additionally write the matches to this file as FoLiA XML, each text is a division and each match an entity (the lexicon is used as the entity class) that refers to the text by offset. Pass '-' to write it to standard output, the other output must then be written to a file with --output.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("threads")
+                        .long("threads")
+                        .short('j')
+                        .help("Number of threads to use. Multiple lexicons will be read in parallel, and multiple text files will be processed in parallel, each thread processes a separate file. The output for each file is buffered in memory and output in the original order. With --tokens, --cjk or --whole-line, a single text is also split into chunks that are scanned in parallel (except for --cjk with --cjk-tile or --oov), the matches are still output in reading order.")
                         .takes_value(true)
                         .default_value("1"))
+                    .arg(Arg::with_name("max-matches")
+                        .long("max-matches")
+                        .help("Output at most this many match offsets per entry (the reported count still reflects the total number of matches), these are the earliest matches in the text. Does not apply to verbose output.")
+                        .takes_value(true))
+                    .arg(Arg::with_name("report-misses")
+                        .long("report-misses")
+                        .help("Output all lexicon entries that were not matched at all (regardless of the frequency threshold). These are output at the end, on lines starting with #miss, followed by the entry and the lexicon name")
+                        .required(false))
+                    .arg(Arg::with_name("distinct-count")
+                        .long("distinct-count")
+                        .help("Output the number of distinct substrings in the text that matched at least once, per lexicon. These are output at the end, on lines starting with #distinct, followed by the lexicon name and the count")
+                        .required(false))
+                    .arg(Arg::with_name("stats")
+                        .long("stats")
+                        .help("Output statistics to standard error after processing: the total number of matches, the number of distinct entries matched and not matched, the number of matches per lexicon, and the elapsed time.")
+                        .required(false))
                     .arg(Arg::with_name("textfile")
                         .help("The filename of the text to operate on (plain text UTF-8, max 4GB unless --tokens is set), use - for standard input.")
                         .multiple_occurrences(true)
-                        .required(true))
-                    .get_matches();
+                        .required_unless_present_any(["validate", "lexicon-hash", "manifest"]))
+}
 
-    let freq_threshold = args
-        .value_of("freq")
-        .expect("frequency threshold")
-        .parse::<usize>()
-        .expect("Frequency threshold must be an integer value >= 0");
+fn run() -> Result<(), LexmatchError> {
+    let starttime = Instant::now();
+    let app = app();
+    let mut args = app.clone().get_matches();
 
-    if !args.is_present("lexicon") && !args.is_present("query") {
-        eprintln!("ERROR: specify either --lexicon or --query");
-        exit(1);
+    if let Some(filename) = args.value_of("config").map(|s| s.to_string()) {
+        //settings from the configuration file are passed as if they were given on the command line (after the program name), and arguments are parsed again
+        let mut argv: Vec<String> = std::env::args().collect();
+        let extra = read_config(&app, &args, &filename)?;
+        argv.splice(1..1, extra);
+        args = app.get_matches_from(argv);
     }
 
-    if args.is_present("no-matches") && args.is_present("verbose") {
-        eprintln!("ERROR: --count-only and --verbose are mutually exclusive");
-        exit(1);
+    //parse and validate all options early so we fail before doing any work
+    let options = Options::from_args(&args)?;
+
+    if options.validate_lexicons {
+        for filename in options.lexicons.iter() {
+            if let Err(err) = validate_lexicon(
+                filename,
+                options.case(),
+                options.csv_lexicon,
+                options.encoding,
+            ) {
+                options.skip_bad_lexicon(filename, err)?;
+            }
+        }
+        return Ok(());
     }
 
-    if (args.is_present("tokens") || args.is_present("cjk")) && args.value_of("freq") != Some("1") {
-        eprintln!("ERROR: Frequency thresholds do not work with --tokens/--cjk");
-        exit(1);
+    //'-' is standard output, like it is standard input for the texts
+    let mut out: Box<dyn Write> = if let Some(filename) =
+        options.output.as_deref().filter(|f| *f != "-")
+    {
+        Box::new(BufWriter::new(
+            File::create(filename).map_err(|err| LexmatchError::File(filename.to_string(), err))?,
+        ))
+    } else {
+        //lock stdout once and buffer, rather than locking and flushing on every write
+        Box::new(BufWriter::new(stdout().lock()))
+    };
+
+    if let Some(offsetsfile) = options.extract.as_deref() {
+        return extract(&mut out, offsetsfile, &options);
     }
 
-    if (!args.is_present("tokens") && !args.is_present("cjk")) && args.is_present("coverage") {
-        eprintln!("ERROR: --coverage can only be used with --tokens or --cjk");
-        exit(1);
+    if options.bytes {
+        return process_bytes(&mut out, &options);
     }
 
-    let mut lexicons: Vec<Lexicon> = if args.is_present("lexicon") {
-        args.get_many("lexicon")
-            .unwrap()
-            .map(|s: &String| {
-                eprintln!("Reading lexicon from {}...", s);
-                read_lexicon(s, args.is_present("no-case")).expect("Parsing lexicon")
-            })
-            .collect()
-    } else {
-        vec![HashSet::new()]
+    let mut phasestart = Instant::now();
+    let mut lexicons: Vec<Lexicon> = Vec::with_capacity(options.lexicons.len());
+    let mut lexiconnames: Vec<String> = Vec::with_capacity(options.lexicons.len());
+    //weights of the entries, indices correspond to the lexicon (only read with --weighted-coverage)
+    let mut weights: Vec<Weights> = Vec::with_capacity(options.lexicons.len());
+    //number of lexicons read so far, for progress reports
+    let done = AtomicUsize::new(0);
+    let load = |filename: &String| {
+        let lexicon = read_lexicon(
+            filename,
+            options.case(),
+            options.csv_lexicon,
+            options.encoding,
+        )
+        .and_then(|lexicon| {
+            if options.weighted_coverage {
+                let lexiconweights = read_weights(
+                    filename,
+                    options.case(),
+                    options.csv_lexicon,
+                    options.encoding,
+                )?;
+                Ok((lexicon, lexiconweights))
+            } else {
+                Ok((lexicon, Weights::new()))
+            }
+        });
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        if lexicon.is_ok() {
+            eprintln!(
+                "Read lexicon from {} ({}/{})",
+                filename,
+                done,
+                options.lexicons.len()
+            );
+        }
+        lexicon
     };
+    //with multiple threads, lexicons are read in parallel, the results are still in the order of the arguments
+    let loaded: Vec<Result<(Lexicon, Weights), std::io::Error>> =
+        if options.threads > 1 && options.lexicons.len() > 1 {
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(options.threads)
+                .build()
+                .expect("Building thread pool")
+                .install(|| options.lexicons.par_iter().map(load).collect())
+        } else {
+            options.lexicons.iter().map(load).collect()
+        };
+    for ((filename, lexiconname), lexicon) in options
+        .lexicons
+        .iter()
+        .zip(options.lexicon_labels())
+        .zip(loaded)
+    {
+        match lexicon {
+            Ok((lexicon, lexiconweights)) => {
+                lexicons.push(lexicon);
+                lexiconnames.push(lexiconname);
+                weights.push(lexiconweights);
+            }
+            Err(err) => options.skip_bad_lexicon(filename, err)?,
+        }
+    }
+    if lexicons.is_empty() && !options.lexicons.is_empty() {
+        return Err(LexmatchError::NoLexicons);
+    }
 
-    let lexiconnames: Vec<String> = if args.is_present("lexicon") {
-        args.get_many("lexicon")
-            .unwrap()
-            .map(|s: &String| s.clone())
-            .collect()
+    //queries form a lexicon of their own, after any lexicons that were loaded
+    if !options.queries.is_empty() || options.query_file.is_some() {
+        let mut querylexicon: Lexicon = options
+            .queries
+            .iter()
+            .map(|query| options.case().convert(query.clone()))
+            .collect();
+        if let Some(filename) = options.query_file.as_deref() {
+            eprintln!("Reading queries from {}...", filename);
+            let queries = read_queries(filename, options.case(), options.encoding)
+                .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+            querylexicon.extend(queries);
+        }
+        lexicons.push(querylexicon);
+        lexiconnames.push("query".to_string());
+        weights.push(Weights::new());
+    }
+
+    if let Some(filename) = options.stoplist.as_deref() {
+        eprintln!("Reading stoplist from {}...", filename);
+        let stoplist = read_lexicon(
+            filename,
+            options.case(),
+            options.csv_lexicon,
+            options.encoding,
+        )
+        .map_err(|err| LexmatchError::File(filename.to_string(), err))?;
+        for lexicon in lexicons.iter_mut() {
+            lexicon.retain(|entry| !stoplist.contains(entry));
+        }
+    }
+    let mut exclude: Option<Lexicon> = if let Some(filename) = options.exclude_lexicon.as_deref() {
+        eprintln!("Reading exclusion lexicon from {}...", filename);
+        Some(
+            read_lexicon(
+                filename,
+                options.case(),
+                options.csv_lexicon,
+                options.encoding,
+            )
+            .map_err(|err| LexmatchError::File(filename.to_string(), err))?,
+        )
     } else {
-        vec!["query".to_string()]
+        None
     };
+    if options.collapse_whitespace {
+        for lexicon in lexicons.iter_mut().chain(exclude.as_mut()) {
+            *lexicon = lexicon
+                .drain()
+                .map(|entry| collapse_whitespace(&entry).0)
+                .collect();
+        }
+        for lexiconweights in weights.iter_mut() {
+            *lexiconweights = lexiconweights
+                .drain()
+                .map(|(entry, weight)| (collapse_whitespace(&entry).0, weight))
+                .collect();
+        }
+    }
+    if options.fold_diacritics.is_some_and(|fold| fold.lexicon()) {
+        for lexicon in lexicons.iter_mut().chain(exclude.as_mut()) {
+            *lexicon = lexicon
+                .drain()
+                .map(|entry| fold_diacritics(&entry))
+                .collect();
+        }
+        for lexiconweights in weights.iter_mut() {
+            *lexiconweights = lexiconweights
+                .drain()
+                .map(|(entry, weight)| (fold_diacritics(&entry), weight))
+                .collect();
+        }
+    }
+    report_phase(&options, "reading lexicons", None, &mut phasestart);
 
-    if args.is_present("query") {
-        let queries: Vec<&str> = args.values_of("query").unwrap().collect();
-        for query in queries {
-            lexicons[0].insert(query.to_string());
+    if options.lexicon_hash {
+        for (lexicon, name) in lexicons.iter().zip(lexiconnames.iter()) {
+            writeln!(out, "{}\t{:016x}", name, lexicon_hash(lexicon))?;
         }
+        out.flush()?;
+        return Ok(());
     }
 
-    let texts: Vec<String> = args
-        .get_many("textfile")
-        .expect("Expected one or more input files")
-        .map(|s: &String| s.clone())
-        .collect();
+    //with --fst, tokens are looked up in finite state transducers built from the lexicons
+    let fsts: Vec<FstLexicon> = if options.fst {
+        eprintln!("Building FSTs...");
+        let fsts = lexicons.iter().map(build_fst).collect();
+        if !(options.stats
+            || options.report_misses
+            || options.weighted_coverage
+            || options.max_gap.is_some())
+        {
+            //the entries are not needed otherwise, so only the FSTs are kept in memory
+            for lexicon in lexicons.iter_mut() {
+                *lexicon = Lexicon::new();
+            }
+        }
+        report_phase(&options, "building FSTs", None, &mut phasestart);
+        fsts
+    } else {
+        Vec::new()
+    };
 
-    let do_coverage = args.is_present("coverage");
-    let min_token_length = args
-        .value_of("min-token-length")
-        .unwrap()
-        .parse::<usize>()
-        .expect("Value must be integer"); //only for coverage computation
+    let texts = &options.textfiles;
 
-    if args.is_present("verbose") || args.is_present("tokens") || args.is_present("cjk") {
-        print!("Text");
-        if lexicons.len() > 1 {
-            println!("\tLexicon");
+    //gaps have no lexicon
+    let columns = verbose_columns(
+        &options,
+        if options.gaps { 1 } else { lexicons.len() },
+        texts.len(),
+    );
+    if options.header_json {
+        print_header(&mut out, &columns, &options)?;
+    }
+
+    if options.lexicon_ids {
+        //legend
+        for (i, lexiconname) in lexiconnames.iter().enumerate() {
+            writeln!(out, "#lexicon\t{}\t{}", i, lexiconname)?;
         }
-        if texts.len() > 1 {
-            println!("\tResource");
+    }
+
+    //with --text-column, the header is output by process_text instead, as the columns that are passed through are only known once the text is read
+    if !options.header_json && options.text_column.is_none() {
+        print_header(&mut out, &columns, &options)?;
+    }
+    if options.density {
+        if options.stable_columns || texts.len() > 1 {
+            write!(out, "Resource\t")?;
         }
-        println!("\tBeginUtf8Offset\tEndUtf8Offset");
+        writeln!(out, "Line\tDensity")?;
     }
 
-    let mut matchcount = vec![0; lexicons.len()]; //indices correspond to the lexicon
-    let mut matched_lexicon = vec![false; lexicons.len()]; //indices correspond to the lexicon
-    let mut totalcount = 0;
+    let mut folia: Option<Box<dyn Write>> = match options.folia.as_deref() {
+        //the other output then goes to a file (see validate())
+        Some("-") => Some(Box::new(BufWriter::new(stdout()))),
+        Some(filename) => Some(Box::new(BufWriter::new(
+            File::create(filename).map_err(|err| LexmatchError::File(filename.to_string(), err))?,
+        ))),
+        None => None,
+    };
+    if let Some(folia) = folia.as_mut() {
+        write_folia_header(folia)?;
+    }
 
-    for textfile in texts.iter() {
-        eprintln!("Reading text from {}...", textfile);
-        let text = read_text(textfile, args.is_present("no-case")).expect("Parsing text");
-
-        if args.is_present("coverage-matrix") {
-            let mut token = String::new();
-            print!("Line\t",);
-            for lexiconname in lexiconnames.iter() {
-                print!("\t{}", lexiconname);
-            }
-            if lexiconnames.len() > 1 {
-                print!("\tTotal");
-            }
-            println!();
-            for line in text.split("\n") {
-                if !line.is_empty() {
-                    totalcount = 0;
-                    for item in &mut matchcount {
-                        //reset matches
-                        *item = 0;
-                    }
-                    print!("{}", line.trim_matches('\r'));
-                    for c in line.chars() {
-                        if c.is_alphanumeric() {
-                            token.push(c);
-                        } else if !token.is_empty() {
-                            if token.chars().any(|c| c.is_alphabetic())
-                                && (min_token_length <= 1
-                                    || token.chars().count() >= min_token_length)
-                            {
-                                totalcount += 1;
-                                for (j, lexicon) in lexicons.iter().enumerate() {
-                                    if lexicon.contains(&token) {
-                                        matchcount[j] += 1;
-                                    }
-                                }
-                            }
-                            token.clear();
-                        }
-                    }
-                    let mut sumcount = 0;
-                    for count in matchcount.iter() {
-                        sumcount += *count;
-                        print!(
-                            "\t{}",
-                            if totalcount == 0 {
-                                0.0
-                            } else {
-                                *count as f64 / totalcount as f64
-                            }
-                        );
-                    }
-                    if lexiconnames.len() > 1 {
-                        print!(
-                            "\t{}",
-                            if totalcount == 0 {
-                                0.0
-                            } else {
-                                sumcount as f64 / totalcount as f64
-                            }
-                        );
-                    }
-                    println!();
-                }
+    let mut counts = Counts::new(lexicons.len());
+    let lexiconset = LexiconSet {
+        lexicons: &lexicons,
+        fsts: &fsts,
+        names: &lexiconnames,
+        exclude: exclude.as_ref(),
+    };
+
+    //with --concat, all texts are processed in a single pass
+    let passes = if options.concat { 1 } else { texts.len() };
+    //number of matches for each text and lexicon (--summary-matrix)
+    let mut textmatchcounts: Vec<Vec<usize>> = Vec::with_capacity(passes);
+    //number of matches for each text and lexicon entry (--entry-matrix)
+    let mut textentrycounts: Vec<HashMap<String, usize>> = Vec::new();
+
+    //with --limit, texts are processed one by one so that output stops at the same match every time
+    if options.threads > 1 && passes > 1 && options.limit.is_none() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()
+            .expect("Building thread pool");
+        //each text is processed with its own output buffer, which is written in the original order afterwards
+        let do_folia = folia.is_some();
+        let results: Vec<Result<_, LexmatchError>> = pool.install(|| {
+            (0..passes)
+                .into_par_iter()
+                .map(|textindex| {
+                    let mut buffer: Vec<u8> = Vec::new();
+                    let mut foliabuffer: Vec<u8> = Vec::new();
+                    let mut textcounts = Counts::new(lexicons.len());
+                    process_text(
+                        &mut buffer,
+                        if do_folia {
+                            Some(&mut foliabuffer)
+                        } else {
+                            None
+                        },
+                        textindex,
+                        &options,
+                        lexiconset,
+                        &mut textcounts,
+                    )?;
+                    Ok((buffer, foliabuffer, textcounts))
+                })
+                .collect()
+        });
+        for result in results {
+            let (buffer, foliabuffer, mut textcounts) = result?;
+            out.write_all(&buffer)?;
+            if let Some(folia) = folia.as_mut() {
+                folia.write_all(&foliabuffer)?;
             }
-        } else if args.is_present("tokens") {
-            let mut token = String::new();
-            let mut begin = 0;
-            for (i, c) in text.char_indices() {
-                if c.is_alphanumeric() {
-                    token.push(c);
-                } else if !token.is_empty() {
-                    if token.chars().any(|c| c.is_alphabetic())
-                        && (min_token_length <= 1 || token.chars().count() >= min_token_length)
-                    {
-                        let mut has_match = false;
-                        for item in &mut matched_lexicon {
-                            //reset matches
-                            *item = false;
-                        }
-                        totalcount += 1;
-                        for (j, lexicon) in lexicons.iter().enumerate() {
-                            if lexicon.contains(&token) {
-                                matched_lexicon[j] = true;
-                                matchcount[j] += 1;
-                                has_match = true;
-                            }
-                        }
-                        if has_match {
-                            print_verbose_match(
-                                &token,
-                                begin,
-                                begin + token.len(),
-                                &matched_lexicon,
-                                &lexiconnames,
-                                texts.len(),
-                                textfile,
-                            );
-                        }
-                    }
-                    token.clear();
-                    begin = i + 1;
-                } else {
-                    begin = i + 1;
-                }
-            }
-        } else if args.is_present("cjk") {
-            let maxlen = args
-                .value_of("cjk")
-                .unwrap()
-                .parse::<usize>()
-                .expect("length for --cjk must be an integer");
-            for begin in 0..text.len() {
-                for l in (1..=maxlen).rev() {
-                    if let Some((lastbyte, c)) = text[begin..].char_indices().nth(l - 1) {
-                        let end = lastbyte + c.len_utf8();
-                        let pattern = &text[begin..end];
-                        let mut has_match = false;
-                        for item in &mut matched_lexicon {
-                            //reset matches
-                            *item = false;
-                        }
-                        for (j, lexicon) in lexicons.iter().enumerate() {
-                            if lexicon.contains(pattern) {
-                                matched_lexicon[j] = true;
-                                matchcount[j] += 1;
-                                has_match = true;
-                            }
-                        }
-                        if has_match {
-                            print_verbose_match(
-                                &pattern,
-                                begin,
-                                end,
-                                &matched_lexicon,
-                                &lexiconnames,
-                                texts.len(),
-                                textfile,
-                            );
-                        }
-                        break; //longest match only
-                    }
-                }
+            textmatchcounts.push(textcounts.matchcount.clone());
+            if options.entry_matrix {
+                textentrycounts.push(std::mem::take(&mut textcounts.entrycount));
             }
-        } else {
-            eprintln!("Building suffix array (this may take a while)...");
-            let suffixtable = build_suffixarray(&text);
-
-            eprintln!("Searching...");
-            for (lexicon, lexiconname) in lexicons.iter().zip(lexiconnames.iter()) {
-                for entry in lexicon.iter() {
-                    if min_token_length >= 1 && entry.chars().count() < min_token_length {
-                        continue;
-                    }
-                    let matches = suffixtable.positions(entry);
-                    let length = entry.as_bytes().len() as u32;
-
-                    if args.is_present("all") {
-                        if matches.len() >= freq_threshold {
-                            if args.is_present("verbose") {
-                                for begin in matches.iter() {
-                                    print_verbose_match(
-                                        &entry,
-                                        *begin as usize,
-                                        *begin as usize + length as usize,
-                                        &matched_lexicon,
-                                        &lexiconnames,
-                                        texts.len(),
-                                        textfile,
-                                    );
-                                }
-                            } else {
-                                print_multi_match(
-                                    &entry,
-                                    matches,
-                                    &lexiconname,
-                                    lexiconnames.len(),
-                                    texts.len(),
-                                    textfile,
-                                    args.is_present("no-matches"),
-                                );
-                            }
-                        }
-                    } else {
-                        //Filter matches that are substrings rather than exact matches
-                        //this is a simplification that ignores the UTF-8 nature of the text, but will work when
-                        //boundaries are simple ascii-like spaces, punctuation etc.
-                        //
-                        let bytetext: &[u8] = text.as_bytes();
-                        let matches_exact: Vec<u32> = matches
-                            .into_iter()
-                            .filter_map(|begin| {
-                                let begin = *begin as usize;
-                                if begin > 0 {
-                                    let c: char = bytetext[begin - 1] as char;
-                                    if c.is_alphanumeric() {
-                                        return None;
-                                    }
-                                }
-                                if (begin + length as usize) < bytetext.len() {
-                                    let c: char = bytetext[begin + length as usize] as char;
-                                    if c.is_alphanumeric() {
-                                        return None;
-                                    }
-                                }
-                                Some(begin as u32)
-                            })
-                            .collect();
-
-                        if matches_exact.len() >= freq_threshold {
-                            if args.is_present("verbose") {
-                                for begin in matches_exact.iter() {
-                                    let end = begin + length;
-                                    print_verbose_match(
-                                        &entry,
-                                        *begin as usize,
-                                        end as usize,
-                                        &matched_lexicon,
-                                        &lexiconnames,
-                                        texts.len(),
-                                        textfile,
-                                    );
-                                }
-                            } else {
-                                print_multi_match(
-                                    &entry,
-                                    matches,
-                                    &lexiconname,
-                                    lexiconnames.len(),
-                                    texts.len(),
-                                    textfile,
-                                    args.is_present("no-matches"),
-                                );
-                            }
-                        }
-                    }
-                }
+            counts.merge(textcounts);
+            if options.coverage {
+                print_coverage(
+                    &mut out,
+                    &options,
+                    &lexicons,
+                    &lexiconnames,
+                    &weights,
+                    &counts,
+                )?;
             }
         }
-        if do_coverage {
-            let mut sumcount = 0;
-            for (i, lexiconname) in lexiconnames.iter().enumerate() {
-                sumcount += matchcount[i];
-                println!(
-                    "#coverage ({} in {}) = {}/{} = {}",
-                    if args.is_present("tokens") {
-                        "tokens"
-                    } else {
-                        "characters"
-                    },
-                    lexiconname,
-                    matchcount[i],
-                    totalcount,
-                    if totalcount == 0 {
-                        0.0
-                    } else {
-                        matchcount[i] as f64 / totalcount as f64
-                    }
-                );
+    } else {
+        if options.threads > 1 {
+            //a single text is scanned in parallel (--tokens, --cjk, --whole-line)
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(options.threads)
+                .build_global()
+                .expect("Building thread pool");
+        }
+        for textindex in 0..passes {
+            let matchcount_before = counts.matchcount.clone();
+            let result = process_text(
+                &mut out,
+                folia.as_deref_mut().map(|f| f as &mut dyn Write),
+                textindex,
+                &options,
+                lexiconset,
+                &mut counts,
+            );
+            //once the --limit is reached, no more texts are processed but everything else is still output
+            let limit_reached = matches!(result, Err(LexmatchError::LimitReached));
+            if !limit_reached {
+                result?;
             }
-            if lexiconnames.len() > 1 {
-                println!(
-                    "#coverage ({} against all) = {}/{} = {}",
-                    if args.is_present("tokens") {
-                        "tokens"
-                    } else {
-                        "characters"
-                    },
-                    sumcount,
-                    totalcount,
-                    if totalcount == 0 {
-                        0.0
-                    } else {
-                        sumcount as f64 / totalcount as f64
-                    }
-                );
+            textmatchcounts.push(
+                counts
+                    .matchcount
+                    .iter()
+                    .zip(matchcount_before.iter())
+                    .map(|(after, before)| after - before)
+                    .collect(),
+            );
+            if options.entry_matrix {
+                textentrycounts.push(std::mem::take(&mut counts.entrycount));
+            }
+            if options.coverage {
+                print_coverage(
+                    &mut out,
+                    &options,
+                    &lexicons,
+                    &lexiconnames,
+                    &weights,
+                    &counts,
+                )?;
+            }
+            if limit_reached {
+                break;
+            }
+        }
+    }
+
+    if options.count_only && options.linear() {
+        //totals per lexicon
+        for (lexiconname, count) in lexiconnames.iter().zip(counts.matchcount.iter()) {
+            writeln!(out, "{}\t{}", lexiconname, count)?;
+        }
+    }
+    if options.summary_matrix {
+        print_summary_matrix(&mut out, texts, &lexiconnames, &textmatchcounts)?;
+    }
+    if options.entry_matrix {
+        print_entry_matrix(&mut out, texts, &textentrycounts)?;
+    }
+    if options.oov {
+        let mut oov: Vec<(String, usize)> = std::mem::take(&mut counts.oov).into_iter().collect();
+        oov.sort_unstable_by(|(a, acount), (b, bcount)| bcount.cmp(acount).then(a.cmp(b)));
+        for (token, count) in oov.iter() {
+            writeln!(out, "#oov\t{}\t{}", token, count)?;
+        }
+    }
+    if options.report_misses {
+        for (j, (lexicon, lexiconname)) in lexicons.iter().zip(lexiconnames.iter()).enumerate() {
+            let mut misses: Vec<&String> = lexicon
+                .iter()
+                .filter(|entry| !counts.matched_entries[j].contains(entry.as_str()))
+                .collect();
+            misses.sort_unstable();
+            for entry in misses {
+                writeln!(out, "#miss\t{}\t{}", entry, lexiconname)?;
             }
         }
     }
+    if options.distinct_count {
+        for (lexiconname, forms) in lexiconnames.iter().zip(counts.matched_forms.iter()) {
+            writeln!(out, "#distinct\t{}\t{}", lexiconname, forms.len())?;
+        }
+    }
+    out.flush()?;
+    if let Some(mut folia) = folia {
+        write_folia_footer(&mut folia)?;
+        folia.flush()?;
+    }
+
+    if options.stats {
+        print_stats(&lexicons, &lexiconnames, &counts, starttime.elapsed());
+    }
+
+    if let Some(min_coverage) = options.min_coverage {
+        let coverage = counts.coverage(options.coverage_denominator);
+        if coverage < min_coverage {
+            return Err(LexmatchError::CoverageBelow(coverage, min_coverage));
+        }
+    }
+
+    if !options.coverage_matrix
+        && !options.density
+        && !options.gaps
+        && counts.matchcount.iter().all(|count| *count == 0)
+    {
+        return Err(LexmatchError::NoMatches);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn match_serializes() {
+        let m = Match {
+            text: "cat".to_string(),
+            begin: 4,
+            end: 7,
+            lexicon: "query".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&m).unwrap(),
+            r#"{"text":"cat","begin":4,"end":7,"lexicon":"query"}"#
+        );
+    }
+
+    #[test]
+    fn offsetmap_whitespace_runs() {
+        let text = "  é  b \n";
+        let (collapsed, offsetmap) = collapse_whitespace(text);
+        assert_eq!(collapsed, " é b ");
+        //the leading run, the multibyte character and the runs after it
+        let expected = [(0, 0), (1, 2), (3, 4), (4, 6), (5, 7)];
+        for (offset, original) in expected {
+            assert_eq!(offsetmap.original(offset), original, "offset {}", offset);
+        }
+        //the end of the text, after a trailing run
+        assert_eq!(offsetmap.original(collapsed.len()), text.len());
+    }
+
+    #[test]
+    fn offsetmap_multibyte() {
+        let text = "café naïve";
+        let (folded, offsetmap) = fold_diacritics_text(text);
+        assert_eq!(folded, "cafe naive");
+        let expected = [(0, 0), (3, 3), (4, 5), (5, 6), (7, 8), (8, 10), (9, 11)];
+        for (offset, original) in expected {
+            assert_eq!(offsetmap.original(offset), original, "offset {}", offset);
+        }
+        assert_eq!(offsetmap.original(folded.len()), text.len());
+    }
+
+    ///A match of the text at the given offset in the lexicons that are marked
+    fn verbose_match(text: &str, begin: usize, matched_lexicons: &[bool]) -> VerboseMatch {
+        VerboseMatch {
+            text: text.to_string(),
+            begin,
+            end: begin + text.len(),
+            matched_lexicons: matched_lexicons.to_vec(),
+        }
+    }
+
+    ///The text, offsets and lexicons of each match
+    fn spans(matches: &[VerboseMatch]) -> Vec<(&str, usize, usize, &[bool])> {
+        matches
+            .iter()
+            .map(|m| {
+                (
+                    m.text.as_str(),
+                    m.begin,
+                    m.end,
+                    m.matched_lexicons.as_slice(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge_adjacent_touching() {
+        let text = "blackbird";
+        let (merged, entries) = merge_adjacent(
+            text,
+            vec![
+                verbose_match("black", 0, &[true, false]),
+                verbose_match("bird", 5, &[false, true]),
+            ],
+        );
+        assert_eq!(spans(&merged), [("blackbird", 0, 9, &[true, true][..])]);
+        assert_eq!(entries, ["black;bird"]);
+    }
+
+    #[test]
+    fn merge_adjacent_overlapping() {
+        let text = "new york city";
+        let (merged, entries) = merge_adjacent(
+            text,
+            vec![
+                verbose_match("new york", 0, &[true]),
+                verbose_match("york city", 4, &[true]),
+                verbose_match("york", 4, &[true]),
+            ],
+        );
+        assert_eq!(spans(&merged), [("new york city", 0, 13, &[true][..])]);
+        assert_eq!(entries, ["new york;york city;york"]);
+    }
+
+    #[test]
+    fn merge_adjacent_whitespace_between() {
+        let text = "new york  city";
+        let (merged, entries) = merge_adjacent(
+            text,
+            vec![
+                verbose_match("new york", 0, &[true]),
+                verbose_match("city", 10, &[true]),
+            ],
+        );
+        assert_eq!(
+            spans(&merged),
+            [
+                ("new york", 0, 8, &[true][..]),
+                ("city", 10, 14, &[true][..])
+            ]
+        );
+        assert_eq!(entries, ["new york", "city"]);
+    }
+
+    #[test]
+    fn retain_longest_ties() {
+        //the same span in two lexicons, both are the longest at their offset
+        let mut matches = vec![
+            verbose_match("new york", 0, &[true, false]),
+            verbose_match("new", 0, &[true, false]),
+            verbose_match("new york", 0, &[false, true]),
+        ];
+        retain_longest(&mut matches, false);
+        let mut spans = spans(&matches);
+        spans.sort();
+        assert_eq!(
+            spans,
+            [
+                ("new york", 0, 8, &[false, true][..]),
+                ("new york", 0, 8, &[true, false][..])
+            ]
+        );
+    }
+
+    #[test]
+    fn retain_longest_nested() {
+        let matches = || {
+            vec![
+                verbose_match("york", 4, &[false, true]),
+                verbose_match("new york city", 0, &[true, false]),
+                verbose_match("york city", 4, &[false, true]),
+                verbose_match("new", 0, &[false, true]),
+            ]
+        };
+        //only the shorter match at the same offset is dropped
+        let mut longest = matches();
+        retain_longest(&mut longest, false);
+        assert_eq!(
+            spans(&longest),
+            [
+                ("new york city", 0, 13, &[true, false][..]),
+                ("york city", 4, 13, &[false, true][..])
+            ]
+        );
+        //matches contained in a match of another lexicon that starts earlier are dropped as well
+        let mut longest = matches();
+        retain_longest(&mut longest, true);
+        assert_eq!(
+            spans(&longest),
+            [("new york city", 0, 13, &[true, false][..])]
+        );
+    }
+
+    ///Parse the options as if they were passed on the command line (with standard input as the text)
+    fn options(args: &[&str]) -> Options {
+        let argv = std::iter::once("lexmatch")
+            .chain(args.iter().copied())
+            .chain(std::iter::once("-"));
+        Options::from_args(&app().get_matches_from(argv)).unwrap()
+    }
+
+    ///The text and offsets of the phrases of the lexicon (passed as queries) found in the text with the given --max-gap
+    fn scan_phrases_with(
+        text: &str,
+        lexicon: &[&str],
+        max_gap: &str,
+    ) -> Vec<(String, usize, usize)> {
+        let mut args = vec!["--tokens", "--max-gap", max_gap];
+        for entry in lexicon {
+            args.extend(["--query", entry]);
+        }
+        let options = options(&args);
+        let lexicons = [lexicon.iter().map(|entry| entry.to_string()).collect()];
+        let phrases = build_phrases(&lexicons, &options);
+        let mut counts = Counts::new(lexicons.len());
+        let mut matches = Vec::new();
+        scan_phrases(
+            text,
+            0..text.len(),
+            &options,
+            &phrases,
+            &mut counts,
+            &mut |matchtext, begin, end, _| {
+                matches.push((matchtext.to_string(), begin, end));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(counts.matchcount, [matches.len()]);
+        matches
+    }
+
+    #[test]
+    fn scan_phrases_no_gap() {
+        let lexicon = ["new york"];
+        assert_eq!(
+            scan_phrases_with("in new york", &lexicon, "0"),
+            [("new york".to_string(), 3, 11)]
+        );
+        assert_eq!(scan_phrases_with("in new old york", &lexicon, "0"), []);
+    }
+
+    #[test]
+    fn scan_phrases_gap_limit() {
+        let lexicon = ["machine learning", "new big apple"];
+        //exactly at the limit, between each token of the entry
+        assert_eq!(
+            scan_phrases_with("machine based deep learning", &lexicon, "2"),
+            [("machine learning".to_string(), 0, 27)]
+        );
+        assert_eq!(
+            scan_phrases_with("the new and big red apple", &lexicon, "1"),
+            [("new big apple".to_string(), 4, 25)]
+        );
+        //one past the limit
+        assert_eq!(
+            scan_phrases_with("machine based deep learning", &lexicon, "1"),
+            []
+        );
+        assert_eq!(
+            scan_phrases_with("the new and big red ripe apple", &lexicon, "1"),
+            []
+        );
+    }
+
+    #[test]
+    fn split_chunks_inside_entry() {
+        let options = options(&["--tokens", "--query", "interlinguistics"]);
+        let lexicons = [Lexicon::from(["interlinguistics".to_string()])];
+        let text = "an interlinguistics b";
+        //most of the split points of this many chunks fall inside the entry
+        let chunks = split_chunks(text, 8, |c| !options.is_alphanumeric(c));
+        assert_eq!(chunks, [0..2, 2..19, 19..21]);
+        let mut counts = Counts::new(lexicons.len());
+        let mut matches = Vec::new();
+        for chunk in chunks {
+            scan_tokens(
+                text,
+                chunk,
+                &options,
+                &lexicons,
+                &[],
+                &mut counts,
+                &mut |matchtext, begin, end, _| {
+                    matches.push((matchtext.to_string(), begin, end));
+                    Ok(())
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(matches, [("interlinguistics".to_string(), 3, 19)]);
+        assert_eq!(counts.matchcount, [1]);
+    }
 }
@@ -0,0 +1,178 @@
+//! A suffix array that is constructed in parallel (--fast-index)
+
+use rayon::prelude::*;
+use std::fmt;
+
+///The text is too large for a suffix array with 32-bit offsets, holds the size of the text in bytes
+#[derive(Debug)]
+pub struct TextTooLarge(pub usize);
+
+impl fmt::Display for TextTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "text of {} bytes is too large for a suffix array, it must be smaller than 4GB",
+            self.0
+        )
+    }
+}
+
+///A suffix array over the bytes of a text, constructed by prefix doubling where each round is a radix sort on the ranks of the previous round.
+///The sort keys of each round are computed in parallel. Provides the same lookup as `suffix::SuffixTable`.
+pub struct SuffixArray<'a> {
+    text: &'a [u8],
+    table: Vec<u32>,
+}
+
+impl<'a> SuffixArray<'a> {
+    ///Build the suffix array, the text must be smaller than 4GB
+    pub fn new(text: &'a str) -> Result<Self, TextTooLarge> {
+        let bytes = text.as_bytes();
+        let n = bytes.len();
+        if n >= u32::MAX as usize {
+            return Err(TextTooLarge(n));
+        }
+        //the rank of each suffix considering only its first k bytes, initially the first byte
+        let mut rank: Vec<u32> = bytes.par_iter().map(|b| *b as u32).collect();
+        let mut table = counting_sort((0..n as u32).collect(), &rank, 256);
+        if n < 2 {
+            return Ok(Self { text: bytes, table });
+        }
+        let mut newrank: Vec<u32> = vec![0; n];
+        let mut k = 1;
+        loop {
+            //order on the k bytes after the first k: suffixes that end early first, then the others in the order of the previous round
+            let mut order: Vec<u32> = Vec::with_capacity(n);
+            order.extend((n - k) as u32..n as u32);
+            order.extend(
+                table
+                    .iter()
+                    .filter(|i| **i as usize >= k)
+                    .map(|i| *i - k as u32),
+            );
+            //then (stably) on the first k bytes, the last suffix of the previous round has the highest rank
+            let classes = rank[table[n - 1] as usize] as usize + 1;
+            table = counting_sort(order, &rank, classes);
+            let keys: Vec<(u32, u32)> = table
+                .par_iter()
+                .map(|i| {
+                    let i = *i as usize;
+                    (rank[i], if i + k < n { rank[i + k] + 1 } else { 0 })
+                })
+                .collect();
+            newrank[table[0] as usize] = 0;
+            let mut r = 0;
+            for j in 1..n {
+                if keys[j - 1] != keys[j] {
+                    r += 1;
+                }
+                newrank[table[j] as usize] = r;
+            }
+            std::mem::swap(&mut rank, &mut newrank);
+            if r as usize == n - 1 {
+                //all ranks are distinct, so the suffixes are fully sorted
+                break;
+            }
+            k *= 2;
+        }
+        Ok(Self { text: bytes, table })
+    }
+
+    ///Returns the begin offsets of all occurrences of the query in the text (in no particular order), an empty query has none
+    pub fn positions(&self, query: &str) -> &[u32] {
+        let query = query.as_bytes();
+        if query.is_empty() {
+            return &[];
+        }
+        let begin = self
+            .table
+            .partition_point(|i| &self.text[*i as usize..] < query);
+        let end = begin
+            + self.table[begin..].partition_point(|i| self.text[*i as usize..].starts_with(query));
+        &self.table[begin..end]
+    }
+}
+
+///Stable counting sort of the suffixes on their rank, ranks must be smaller than the number of classes
+fn counting_sort(suffixes: Vec<u32>, rank: &[u32], classes: usize) -> Vec<u32> {
+    let mut starts = vec![0; classes + 1];
+    for i in suffixes.iter() {
+        starts[rank[*i as usize] as usize + 1] += 1;
+    }
+    for c in 1..=classes {
+        starts[c] += starts[c - 1];
+    }
+    let mut sorted = vec![0; suffixes.len()];
+    for i in suffixes {
+        let start = &mut starts[rank[i as usize] as usize];
+        sorted[*start] = i;
+        *start += 1;
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuffixArray;
+    use suffix::SuffixTable;
+
+    ///Check that the positions of each query are the same as those of the suffix crate
+    fn assert_same_positions(text: &str, queries: &[&str]) {
+        let array = SuffixArray::new(text).unwrap();
+        let table = SuffixTable::new(text);
+        for query in queries {
+            let mut positions = array.positions(query).to_vec();
+            let mut expected = table.positions(query).to_vec();
+            positions.sort_unstable();
+            expected.sort_unstable();
+            assert_eq!(
+                positions, expected,
+                "positions of {:?} in {:?}",
+                query, text
+            );
+        }
+    }
+
+    #[test]
+    fn multibyte_text() {
+        assert_same_positions(
+            "我爱北京天安门，北京欢迎你。café naïve café",
+            &["北京", "天安门", "京", "café", "é", "ï", "你。", "上海"],
+        );
+    }
+
+    #[test]
+    fn repeated_substrings() {
+        assert_same_positions(
+            "abababababa banana bananas",
+            &[
+                "a", "ab", "aba", "abab", "ba", "ana", "anana", "banana", "nas", "c",
+            ],
+        );
+        assert_same_positions("aaaaaaaa", &["a", "aa", "aaa", "aaaaaaaa", "aaaaaaaaa"]);
+    }
+
+    #[test]
+    fn suffixes_are_sorted() {
+        let text = "the cat sat on the mat, the cat sat on the hat\n".repeat(20);
+        let array = SuffixArray::new(&text).unwrap();
+        let bytes = text.as_bytes();
+        assert_eq!(array.table.len(), bytes.len());
+        assert!(array
+            .table
+            .windows(2)
+            .all(|pair| bytes[pair[0] as usize..] < bytes[pair[1] as usize..]));
+    }
+
+    #[test]
+    fn empty_query() {
+        assert_same_positions("some text", &[""]);
+        assert_same_positions("", &["", "a"]);
+    }
+
+    #[test]
+    fn query_longer_than_text() {
+        assert_same_positions("abc", &["abcd", "abcabc", "zabc"]);
+        assert_same_positions("x", &["xx"]);
+    }
+}
@@ -0,0 +1,241 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+///Run lexmatch with the given arguments on the given input text (passed via stdin), returns the standard output
+fn lexmatch(args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lexmatch"))
+        .args(args)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("running lexmatch");
+    child
+        .stdin
+        .take()
+        .expect("stdin")
+        .write_all(input.as_bytes())
+        .expect("writing input");
+    let output = child.wait_with_output().expect("waiting for lexmatch");
+    String::from_utf8(output.stdout).expect("output must be valid UTF-8")
+}
+
+#[test]
+fn cjk_mixed_with_ascii_punctuation() {
+    let output = lexmatch(
+        &["--cjk", "3", "-q", "北京", "-q", "天安门"],
+        "我爱北京天安门, and 北京!",
+    );
+    assert_eq!(
+        output,
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\n北京\t6\t12\n天安门\t12\t21\n北京\t27\t33\n"
+    );
+}
+
+#[test]
+fn tokens_after_multibyte_delimiters() {
+    assert_eq!(
+        lexmatch(
+            &["--tokens", "-q", "quote", "-q", "dash"],
+            "a “quote”—dash\n"
+        ),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\nquote\t5\t10\ndash\t16\t20\n"
+    );
+}
+
+#[test]
+fn coverage_matrix_header_matches_rows() {
+    let lexicon = std::env::temp_dir().join(format!("lexmatch-test-{}.lst", std::process::id()));
+    std::fs::write(&lexicon, "good\nbad\n").expect("writing lexicon");
+    let output = lexmatch(
+        &[
+            "--coverage-matrix",
+            "--lexicon",
+            lexicon.to_str().unwrap(),
+            "-q",
+            "quite",
+        ],
+        "Is this good or bad?\nIt is quite good\n",
+    );
+    std::fs::remove_file(&lexicon).ok();
+    let rows: Vec<Vec<&str>> = output
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(rows.len(), 3);
+    assert_eq!(rows[0][0], "Line");
+    assert_eq!(rows[0][2], "query");
+    assert_eq!(rows[0][3], "Total");
+    for row in rows.iter() {
+        assert_eq!(row.len(), rows[0].len());
+    }
+    assert_eq!(rows[1][1..], ["0.4", "0", "0.4"]);
+    assert_eq!(rows[2][1..], ["0.25", "0.25", "0.5"]);
+}
+
+#[test]
+fn exact_phrase_does_not_split_tokens() {
+    let input = "I love New York, not the New Yorker.\n";
+    assert_eq!(
+        lexmatch(&["--exact-phrase", "--verbose", "-q", "New York"], input),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\nNew York\t7\t15\n"
+    );
+    assert_eq!(
+        lexmatch(&["--all", "--verbose", "--sort", "-q", "New York"], input),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\nNew York\t7\t15\nNew York\t25\t33\n"
+    );
+}
+
+#[test]
+fn exact_phrase_allows_punctuation_at_edges() {
+    let input = "see example.com or example.community\n";
+    assert_eq!(
+        lexmatch(&["--verbose", "-q", ".com"], input),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\n"
+    );
+    assert_eq!(
+        lexmatch(&["--exact-phrase", "--verbose", "-q", ".com"], input),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\n.com\t11\t15\n"
+    );
+}
+
+#[test]
+fn coverage_against_chars_counts_matched_chars() {
+    let input = "The cat saw the dog and a bird 42\n";
+    let args = [
+        "--coverage-denominator",
+        "chars",
+        "-q",
+        "the",
+        "-q",
+        "cat",
+        "-q",
+        "dog",
+    ];
+    let output = lexmatch(&[&["--tokens", "--coverage"], &args[..]].concat(), input);
+    assert_eq!(
+        output.lines().last(),
+        Some("#coverage (characters in query) = 9/25 = 0.36")
+    );
+    assert_eq!(
+        lexmatch(&[&["--coverage-matrix"], &args[..]].concat(), input),
+        "Line\tquery\nThe cat saw the dog and a bird 42\t0.36\n"
+    );
+}
+
+#[test]
+fn output_dash_is_stdout() {
+    assert_eq!(
+        lexmatch(&["--output", "-", "--tokens", "-q", "cat"], "the cat\n"),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\ncat\t4\t7\n"
+    );
+    assert!(!std::path::Path::new("-").exists());
+}
+
+#[test]
+fn folia_dash_requires_output() {
+    let status = Command::new(env!("CARGO_BIN_EXE_lexmatch"))
+        .args(["--folia", "-", "-q", "cat", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .expect("running lexmatch");
+    assert!(!status.success());
+    assert!(!std::path::Path::new("-").exists());
+}
+
+#[test]
+fn folia_output() {
+    let tsv = std::env::temp_dir().join(format!("lexmatch-test-{}.tsv", std::process::id()));
+    let output = lexmatch(
+        &[
+            "--folia",
+            "-",
+            "--output",
+            tsv.to_str().unwrap(),
+            "-q",
+            "cat",
+        ],
+        "the cat sat",
+    );
+    let matches = std::fs::read_to_string(&tsv).expect("reading output");
+    std::fs::remove_file(&tsv).ok();
+    assert!(output.starts_with("<?xml"));
+    assert!(output.contains("<t>the cat sat</t>"));
+    assert!(output.contains(
+        "<entity xml:id=\"lexmatch.div.1.entity.1\" class=\"query\">\n          <t offset=\"4\">cat</t>\n        </entity>"
+    ));
+    assert!(output.trim_end().ends_with("</FoLiA>"));
+    assert_eq!(matches, "cat\t1\t4\n");
+}
+
+#[test]
+fn max_matches_keeps_earliest() {
+    assert_eq!(
+        lexmatch(&["--max-matches", "1", "-q", "kat"], "kat z kat a"),
+        "kat\t2\t0\n"
+    );
+    assert_eq!(
+        lexmatch(
+            &["--max-matches", "2", "--json-grouped", "-q", "kat"],
+            "kat z kat a kat b"
+        ),
+        "{\"entry\": \"kat\", \"lexicon\": \"query\", \"resource\": \"-\", \"count\": 3, \"positions\": [0, 6]}\n"
+    );
+}
+
+#[test]
+fn text_column_header_matches_rows() {
+    let output = lexmatch(
+        &["--text-column", "2", "--tokens", "-q", "cat", "-q", "dog"],
+        "1\tthe cat\tx\n2\ta dog\ty\n",
+    );
+    assert_eq!(
+        output,
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\tColumn1\tColumn3\ncat\t4\t7\t1\tx\ndog\t2\t5\t2\ty\n"
+    );
+    let widths: Vec<usize> = output
+        .lines()
+        .map(|line| line.split('\t').count())
+        .collect();
+    assert!(widths.iter().all(|width| *width == widths[0]));
+}
+
+#[test]
+fn no_numbers_whole_line() {
+    assert_eq!(
+        lexmatch(
+            &["--whole-line", "--coverage", "--no-numbers", "-q", "2023", "-q", "in 2023"],
+            "in 2023\n2023\n"
+        ),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\nin 2023\t0\t7\n#coverage (lines in query) = 1/1 = 1\n"
+    );
+}
+
+#[test]
+fn fold_diacritics_text_tokens_and_lines() {
+    assert_eq!(
+        lexmatch(
+            &[
+                "--tokens",
+                "--fold-diacritics",
+                "text",
+                "-q",
+                "cafe",
+                "-q",
+                "creme"
+            ],
+            "Le café crème\n"
+        ),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\ncafé\t3\t8\ncrème\t9\t15\n"
+    );
+    assert_eq!(
+        lexmatch(
+            &["--whole-line", "--fold-diacritics", "both", "-q", "naïve"],
+            "Le café crème\n naive \n"
+        ),
+        "Text\tBeginUtf8Offset\tEndUtf8Offset\nnaive\t17\t22\n"
+    );
+}